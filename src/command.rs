@@ -0,0 +1,154 @@
+use crate::queue::QueuedWriter;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Minimum time between restarts so a command that exits straight away doesn't spin
+const RESTART_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a command gets to exit after its stdin is closed before it's killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A running instance of the command and the writer feeding its stdin
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    stdin: QueuedWriter,
+}
+
+/// Runs a command and writes everything written to this to its stdin through a `QueuedWriter`.
+/// The command's stderr is logged and its stdout is inherited.
+#[derive(Debug)]
+pub struct CommandSink {
+    argv: Vec<String>,
+    name: String,
+    restart: bool,
+    process: Option<Process>,
+    started: Instant,
+}
+
+impl CommandSink {
+    pub fn spawn(argv: &[String], name: &str, restart: bool) -> io::Result<Self> {
+        if argv.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the command for {} is empty", name),
+            ));
+        }
+        let mut sink = Self {
+            argv: argv.to_vec(),
+            name: name.to_string(),
+            restart,
+            process: None,
+            started: Instant::now(),
+        };
+        sink.start()?;
+        Ok(sink)
+    }
+
+    /// The command line, used in log messages
+    pub fn command_line(&self) -> String {
+        self.argv.join(" ")
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        let mut child = Command::new(&self.argv[0])
+            .args(&self.argv[1..])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let program = self.argv[0].clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                warn!("{}: {}", program, line);
+            }
+        });
+        // The command exiting is noticed and reported on the next write
+        let stdin = QueuedWriter::spawn(stdin, |_| {});
+        info!("Started '{}' for {}", self.command_line(), self.name);
+        self.process = Some(Process { child, stdin });
+        self.started = Instant::now();
+        Ok(())
+    }
+
+    /// Handles the command having exited, it's restarted on a later write if allowed
+    fn exited(&mut self) -> io::Result<()> {
+        let mut process = match self.process.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let status = process.child.wait()?;
+        let _ = process.stdin.close().join();
+        if !self.restart {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("'{}' exited with {}", self.command_line(), status),
+            ));
+        }
+        warn!(
+            "'{}' for {} exited with {}, restarting it",
+            self.command_line(),
+            self.name,
+            status
+        );
+        Ok(())
+    }
+
+    /// Closes the command's stdin and waits for it to exit, killing it if it doesn't exit in time
+    /// or has stopped reading its input
+    pub fn finish(&mut self) -> io::Result<()> {
+        let Process { mut child, stdin } = match self.process.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let writer = stdin.close();
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "'{}' didn't exit after its input was closed, killing it",
+                    self.command_line()
+                );
+                child.kill()?;
+                break child.wait()?;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // A writer stuck on a pipe something else still holds open is left behind
+        if writer.is_finished() {
+            let _ = writer.join();
+        }
+        if !status.success() {
+            warn!("'{}' exited with {}", self.command_line(), status);
+        }
+        Ok(())
+    }
+}
+
+impl Write for CommandSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.process.is_none() {
+            // Data is dropped until the command has been restarted
+            if !self.restart || self.started.elapsed() < RESTART_INTERVAL {
+                return Ok(buf.len());
+            }
+            self.start()?;
+        }
+        let process = self.process.as_mut().expect("command is running");
+        let reader = format_args!("'{}' for {}", self.argv[0], self.name);
+        if !process.stdin.send(buf.into(), reader) {
+            self.exited()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
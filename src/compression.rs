@@ -0,0 +1,102 @@
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Compressed streams are flushed this often so a crash only loses the last moments of data
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Streaming compression applied to a channel's output file
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+enum Inner {
+    Plain(fs::File),
+    Gzip(GzEncoder<fs::File>),
+    Zstd(zstd::Encoder<'static, fs::File>),
+}
+
+/// Writes to a file through the configured compression. Compressed streams are periodically
+/// flushed so everything up to the last flush can be recovered if the logger dies, `finish`
+/// should be called to end the stream properly.
+pub struct Encoder {
+    inner: Inner,
+    last_flush: Instant,
+    /// Whether anything has been written since the last flush
+    pending: bool,
+}
+
+impl fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.inner {
+            Inner::Plain(_) => "Plain",
+            Inner::Gzip(_) => "Gzip",
+            Inner::Zstd(_) => "Zstd",
+        };
+        f.debug_struct("Encoder").field("kind", &kind).finish()
+    }
+}
+
+impl Encoder {
+    pub fn new(file: fs::File, compression: Compression) -> io::Result<Self> {
+        let inner = match compression {
+            Compression::None => Inner::Plain(file),
+            Compression::Gzip => Inner::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(Self {
+            inner,
+            last_flush: Instant::now(),
+            pending: false,
+        })
+    }
+
+    /// Flushes the compressed stream if data has been waiting for longer than the flush
+    /// interval, called regularly so data read just before a channel goes quiet isn't held back
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.pending && self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of the compressed stream. Nothing should be written after this
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Plain(f) => f.flush(),
+            Inner::Gzip(e) => e.try_finish(),
+            Inner::Zstd(e) => e.do_finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.inner {
+            Inner::Plain(f) => return f.write(buf),
+            Inner::Gzip(e) => e.write(buf)?,
+            Inner::Zstd(e) => e.write(buf)?,
+        };
+        self.pending = true;
+        self.flush_if_due()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.pending = false;
+        match &mut self.inner {
+            Inner::Plain(f) => f.flush(),
+            Inner::Gzip(e) => e.flush(),
+            Inner::Zstd(e) => e.flush(),
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use crate::output::{self, stem_numbered_path, OpenPolicy};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+/// Magic number at the start of every LLVM raw profile, in the byte order of either endianness
+const PROFRAW_MAGIC: u64 = 0xff6c_7072_6f66_7281;
+const MAGIC_LEN: usize = 8;
+
+fn find_magic(data: &[u8]) -> Option<usize> {
+    let le = PROFRAW_MAGIC.to_le_bytes();
+    let be = PROFRAW_MAGIC.to_be_bytes();
+    data.windows(MAGIC_LEN).position(|x| x == le || x == be)
+}
+
+/// Splits a minicov style coverage stream into one `.profraw` file per run. Each time the target
+/// dumps its counters it sends a complete raw profile, so a run starts wherever the profile
+/// magic number is seen and ends at the next one or when the capture ends.
+#[derive(Debug)]
+pub struct CoverageWriter {
+    /// Template for run files, run `n` is written to it with `-n` added to the file stem
+    path: PathBuf,
+    policy: OpenPolicy,
+    /// Where to write the lcov report for all the runs when the capture ends
+    lcov: Option<PathBuf>,
+    binary: Option<PathBuf>,
+    current: Option<fs::File>,
+    runs: Vec<PathBuf>,
+    /// Data that might be the start of a magic number split across reads
+    pending: Vec<u8>,
+    /// Set while discarding data that isn't part of a run so we only warn once
+    discarding: bool,
+}
+
+impl CoverageWriter {
+    pub fn new(
+        path: PathBuf,
+        policy: OpenPolicy,
+        lcov: Option<PathBuf>,
+        binary: Option<&Path>,
+    ) -> Self {
+        if lcov.is_some() && binary.is_none() {
+            warn!("An lcov report needs the ELF passed via --binary, it won't be written");
+        }
+        // A second profile appended to a run file would corrupt it, so keep old runs alongside
+        let policy = if policy == OpenPolicy::Append {
+            warn!(
+                "Coverage runs can't be appended to, writing new runs for {} to unique files",
+                path.display()
+            );
+            OpenPolicy::Unique
+        } else {
+            policy
+        };
+        Self {
+            path,
+            policy,
+            lcov,
+            binary: binary.map(Path::to_path_buf),
+            current: None,
+            runs: vec![],
+            pending: vec![],
+            discarding: false,
+        }
+    }
+
+    /// Base path of the run files, used in log messages
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_run(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(file) => file.write_all(data),
+            None if data.is_empty() => Ok(()),
+            None => {
+                if !self.discarding {
+                    warn!(
+                        "Discarding coverage data that isn't part of a run for {}",
+                        self.path.display()
+                    );
+                    self.discarding = true;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn start_run(&mut self) -> io::Result<()> {
+        self.close_run()?;
+        let path = stem_numbered_path(&self.path, self.runs.len());
+        let (path, file) = output::open(&path, self.policy)?;
+        info!("Writing coverage run to {}", path.display());
+        self.current = Some(file);
+        self.runs.push(path);
+        self.discarding = false;
+        Ok(())
+    }
+
+    fn close_run(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the current run file, any data until the next run starts is discarded
+    pub fn end_run(&mut self) -> io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.write_run(&pending)?;
+        self.close_run()
+    }
+
+    /// Ends the last run and merges all of them into an lcov report if one was asked for
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.end_run()?;
+        let (lcov, binary) = match (self.lcov.as_ref(), self.binary.as_ref()) {
+            (Some(lcov), Some(binary)) => (lcov, binary),
+            _ => return Ok(()),
+        };
+        if self.runs.is_empty() {
+            warn!("No coverage runs received, not writing {}", lcov.display());
+            return Ok(());
+        }
+        let profdata = lcov.with_extension("profdata");
+        // Runs cut short by the capture ending are skipped rather than failing the merge
+        run_tool(
+            Command::new("llvm-profdata")
+                .args(["merge", "-sparse", "--failure-mode=all", "-o"])
+                .arg(&profdata)
+                .args(&self.runs),
+        )?;
+        let report = fs::File::create(lcov)?;
+        run_tool(
+            Command::new("llvm-cov")
+                .args(["export", "--format=lcov", "--instr-profile"])
+                .arg(&profdata)
+                .arg(binary)
+                .stdout(report),
+        )?;
+        info!(
+            "Wrote lcov report for {} runs to {}",
+            self.runs.len(),
+            lcov.display()
+        );
+        Ok(())
+    }
+}
+
+fn run_tool(command: &mut Command) -> io::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("couldn't run {}: {}", program, e)))?;
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("{}: {}", program, line);
+    }
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, output.status
+        )))
+    }
+}
+
+impl Write for CoverageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(start) = find_magic(&self.pending) {
+            let before: Vec<u8> = self.pending.drain(..start).collect();
+            self.write_run(&before)?;
+            let rest = self.pending.split_off(MAGIC_LEN);
+            let magic = std::mem::replace(&mut self.pending, rest);
+            self.start_run()?;
+            self.write_run(&magic)?;
+        }
+        // Hold back anything that could be the start of a magic number split across reads
+        let keep = self.pending.len().min(MAGIC_LEN - 1);
+        let ready: Vec<u8> = self.pending.drain(..self.pending.len() - keep).collect();
+        self.write_run(&ready)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(chunks: &[&[u8]]) -> (tempfile::TempDir, Vec<Vec<u8>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coverage.profraw");
+        let mut writer = CoverageWriter::new(path.clone(), OpenPolicy::Truncate, None, None);
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        let runs = (0..)
+            .map(|n| stem_numbered_path(&path, n))
+            .take_while(|x| x.exists())
+            .map(|x| fs::read(x).unwrap())
+            .collect();
+        (dir, runs)
+    }
+
+    fn run(magic: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut run = magic.to_vec();
+        run.extend_from_slice(data);
+        run
+    }
+
+    #[test]
+    fn splits_runs() {
+        let le = PROFRAW_MAGIC.to_le_bytes();
+        let first = run(le, b"first run");
+        let second = run(le, b"second");
+        let mut stream = b"boot log".to_vec();
+        stream.extend_from_slice(&first);
+        stream.extend_from_slice(&second);
+        let (_dir, runs) = capture(&[&stream]);
+        assert_eq!(runs, vec![first, second]);
+    }
+
+    #[test]
+    fn magic_split_across_reads() {
+        let be = PROFRAW_MAGIC.to_be_bytes();
+        let first = run(be, &[0xff, 0x6c, 0x00]);
+        let second = run(be, &[0x01, 0x02]);
+        let mut stream = first.clone();
+        stream.extend_from_slice(&second);
+        // Split inside the second magic number, and one byte at a time through the first
+        let (head, tail) = stream.split_at(first.len() + 3);
+        let mut chunks = head.chunks(1).collect::<Vec<_>>();
+        chunks.push(tail);
+        let (_dir, runs) = capture(&chunks);
+        assert_eq!(runs, vec![first, second]);
+    }
+
+    #[test]
+    fn data_before_first_run_discarded() {
+        let (_dir, runs) = capture(&[b"no coverage here", &[0xff, 0x6c]]);
+        assert!(runs.is_empty());
+    }
+
+    #[test]
+    fn end_run_closes_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coverage.profraw");
+        let mut writer = CoverageWriter::new(path.clone(), OpenPolicy::Truncate, None, None);
+        let le = PROFRAW_MAGIC.to_le_bytes();
+        writer.write_all(&run(le, b"abc")).unwrap();
+        writer.end_run().unwrap();
+        // Data after a gap isn't part of any run until the next magic number
+        writer.write_all(b"lost").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            fs::read(stem_numbered_path(&path, 0)).unwrap(),
+            run(le, b"abc")
+        );
+        assert!(!stem_numbered_path(&path, 1).exists());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::records::RecordDecoder;
+use defmt_decoder::{DecodeError, Locations, StreamDecoder, Table};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::warn;
+
+/// How the data read from a channel is turned into what's written to its file
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Write the bytes as they're read from the target
+    #[default]
+    Raw,
+    /// Decode defmt frames using the table in the `--binary` ELF
+    Defmt,
+    /// Write each read, or each frame when the channel has framing, as a line of hex bytes
+    Hex,
+    /// Decode each frame as CBOR and write it as a line of JSON
+    Cbor,
+    /// Decode each frame as postcard using `schema` and write it as a line of JSON
+    Postcard,
+    /// Split a minicov coverage stream into `.profraw` files, one per run
+    Coverage,
+}
+
+/// The defmt table and source locations from an ELF. This lives for the rest of the program so
+/// the stream decoders borrowing from it can be stored in the sinks.
+pub struct DefmtTable {
+    table: Table,
+    locations: Option<Locations>,
+}
+
+impl DefmtTable {
+    pub fn load(elf: &Path) -> Result<&'static Self, String> {
+        let bytes = fs::read(elf).map_err(|e| format!("couldn't read {}: {}", elf.display(), e))?;
+        let table = Table::parse(&bytes)
+            .map_err(|e| format!("couldn't parse the defmt table: {}", e))?
+            .ok_or_else(|| format!("{} doesn't contain a defmt table", elf.display()))?;
+        let locations = match table.get_locations(&bytes) {
+            Ok(locations) if !locations.is_empty() => Some(locations),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Couldn't load defmt locations: {}", e);
+                None
+            }
+        };
+        Ok(Box::leak(Box::new(Self { table, locations })))
+    }
+}
+
+pub struct DefmtDecoder {
+    table: &'static DefmtTable,
+    decoder: Box<dyn StreamDecoder + 'static>,
+    show_location: bool,
+}
+
+impl fmt::Debug for DefmtDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DefmtDecoder")
+            .field("show_location", &self.show_location)
+            .finish()
+    }
+}
+
+impl DefmtDecoder {
+    pub fn new(table: &'static DefmtTable, show_location: bool) -> Self {
+        Self {
+            table,
+            decoder: table.table.new_stream_decoder(),
+            show_location,
+        }
+    }
+
+    /// Feeds in data from the channel and writes a line for every complete frame
+    pub fn decode(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.decoder.received(data);
+        loop {
+            match self.decoder.decode() {
+                Ok(frame) => {
+                    write!(out, "{}", frame.display(false))?;
+                    let location = self
+                        .table
+                        .locations
+                        .as_ref()
+                        .filter(|_| self.show_location)
+                        .and_then(|x| x.get(&frame.index()));
+                    if let Some(loc) = location {
+                        write!(out, " ({}:{})", loc.file.display(), loc.line)?;
+                    }
+                    writeln!(out)?;
+                }
+                Err(DecodeError::UnexpectedEof) => break,
+                Err(DecodeError::Malformed) => {
+                    warn!("Malformed defmt frame");
+                    writeln!(out, "(malformed defmt frame)")?;
+                    if !self.table.table.encoding().can_recover() {
+                        // Without rzcobs there's no way to find the next frame boundary
+                        self.decoder = self.table.table.new_stream_decoder();
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decoding state for a channel
+#[derive(Debug)]
+pub enum Decoder {
+    Raw,
+    Hex,
+    Defmt(DefmtDecoder),
+    Record(RecordDecoder),
+}
+
+impl Decoder {
+    pub fn write(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::Raw => out.write_all(data),
+            Self::Hex => {
+                let line = data
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(out, "{}", line)
+            }
+            Self::Defmt(d) => d.decode(data, out),
+            Self::Record(r) => r.write(data, out),
+        }
+    }
+
+    /// Drops any partially decoded data, used when data may have been lost
+    pub fn reset(&mut self) {
+        if let Self::Defmt(d) = self {
+            d.decoder = d.table.table.new_stream_decoder();
+        }
+    }
+}
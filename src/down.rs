@@ -0,0 +1,182 @@
+use probe_rs::Core;
+use probe_rs_rtt::DownChannel;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Number of chunks the reader thread can get ahead of the target before it blocks
+const QUEUE_DEPTH: usize = 4;
+/// How long to wait before reading a non-blocking source again when it has no data
+const NON_BLOCKING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where data sent to a down channel comes from. A path of `-` means stdin, anything else is
+/// opened as a file so FIFOs work as well.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(from = "PathBuf")]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        if path.as_os_str() == "-" {
+            Self::Stdin
+        } else {
+            Self::File(path)
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Stdin => write!(f, "stdin"),
+            Self::File(p) => write!(f, "{}", p.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownConfig {
+    pub down: usize,
+    pub name: String,
+    pub source: Source,
+}
+
+/// Parses the `CHANNEL=SOURCE` form used on the command line
+impl FromStr for DownConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (down, source) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected CHANNEL=SOURCE, got '{}'", s))?;
+        let down = down
+            .parse()
+            .map_err(|e| format!("invalid down channel '{}': {}", down, e))?;
+        Ok(Self {
+            down,
+            name: format!("down{}", down),
+            source: PathBuf::from(source).into(),
+        })
+    }
+}
+
+/// Streams data from a `Source` into a down channel. The source is read on its own thread into a
+/// bounded queue, when the target buffer is full the pending data is held here and the reader
+/// blocks on the queue so back-pressure reaches the source.
+#[derive(Debug)]
+pub struct ChannelSource {
+    /// Core whose RTT control block the channel belongs to
+    pub core: usize,
+    pub channel: DownChannel,
+    pub name: String,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    offset: usize,
+    pub finished: bool,
+}
+
+impl ChannelSource {
+    pub fn new(core: usize, channel: DownChannel, name: String, source: Source) -> Self {
+        let description = source.to_string();
+        Self::spawn(core, channel, name, description, move || match source {
+            Source::Stdin => Ok(Box::new(io::stdin())),
+            Source::File(path) => Ok(Box::new(fs::File::open(path)?)),
+        })
+    }
+
+    /// Streams from an already open reader. If it's non-blocking it's polled until it has data
+    pub fn from_reader(
+        core: usize,
+        channel: DownChannel,
+        name: String,
+        description: String,
+        reader: impl Read + Send + 'static,
+    ) -> Self {
+        Self::spawn(core, channel, name, description, move || {
+            Ok(Box::new(reader))
+        })
+    }
+
+    fn spawn(
+        core: usize,
+        channel: DownChannel,
+        name: String,
+        description: String,
+        open: impl FnOnce() -> io::Result<Box<dyn Read>> + Send + 'static,
+    ) -> Self {
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let buffer_size = channel.buffer_size().max(1);
+        let thread_name = name.clone();
+        thread::spawn(move || {
+            let mut reader = match open() {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("Couldn't open {} for {}: {}", description, thread_name, e);
+                    return;
+                }
+            };
+            info!("Streaming {} into {}", description, thread_name);
+            let mut buffer = vec![0u8; buffer_size];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buffer[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(NON_BLOCKING_POLL_INTERVAL);
+                    }
+                    Err(e) => {
+                        error!("Failed to read {} for {}: {}", description, thread_name, e);
+                        break;
+                    }
+                }
+            }
+            info!("Reached the end of {} for {}", description, thread_name);
+        });
+        Self {
+            core,
+            channel,
+            name,
+            rx,
+            pending: vec![],
+            offset: 0,
+            finished: false,
+        }
+    }
+
+    /// Writes as much pending data as the target will accept, returning the number of bytes
+    /// written.
+    pub fn poll(&mut self, core: &mut Core) -> Result<usize, probe_rs_rtt::Error> {
+        if self.offset >= self.pending.len() {
+            match self.rx.try_recv() {
+                Ok(data) => {
+                    self.pending = data;
+                    self.offset = 0;
+                }
+                Err(TryRecvError::Empty) => return Ok(0),
+                Err(TryRecvError::Disconnected) => {
+                    info!("Finished sending data to {}", self.name);
+                    self.finished = true;
+                    return Ok(0);
+                }
+            }
+        }
+        let written = self.channel.write(core, &self.pending[self.offset..])?;
+        self.offset += written;
+        Ok(written)
+    }
+}
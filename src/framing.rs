@@ -0,0 +1,376 @@
+use serde::Deserialize;
+use std::io;
+use tracing::warn;
+
+/// Frames bigger than this are treated as corrupt so a bad length or missing delimiter can't
+/// make us buffer forever
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// How records are delimited in a channel's byte stream
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// COBS encoded frames each followed by a `0x00` delimiter
+    Cobs,
+    /// SLIP (RFC 1055) frames ended by `0xc0`
+    Slip,
+    /// Each frame is preceded by its length as a little endian u16
+    U16Le,
+    /// Each frame is preceded by its length as a big endian u16
+    U16Be,
+    /// Each frame is preceded by its length as a little endian u32
+    U32Le,
+    /// Each frame is preceded by its length as a big endian u32
+    U32Be,
+}
+
+impl Framing {
+    /// Size of the length prefix, if the frames have one
+    fn prefix_len(self) -> Option<usize> {
+        match self {
+            Self::Cobs | Self::Slip => None,
+            Self::U16Le | Self::U16Be => Some(2),
+            Self::U32Le | Self::U32Be => Some(4),
+        }
+    }
+
+    fn frame_len(self, prefix: &[u8]) -> usize {
+        match self {
+            Self::U16Le => u16::from_le_bytes([prefix[0], prefix[1]]) as usize,
+            Self::U16Be => u16::from_be_bytes([prefix[0], prefix[1]]) as usize,
+            Self::U32Le => {
+                u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize
+            }
+            Self::U32Be => {
+                u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize
+            }
+            Self::Cobs | Self::Slip => unreachable!("frame has no length prefix"),
+        }
+    }
+}
+
+/// Decodes a COBS frame without its `0x00` delimiter
+fn cobs_decode(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut frame = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 {
+            return Err("zero byte inside frame");
+        }
+        let end = i + code;
+        if end > data.len() {
+            return Err("block runs past the end of the frame");
+        }
+        frame.extend_from_slice(&data[i + 1..end]);
+        i = end;
+        if code < 0xff && i < data.len() {
+            frame.push(0);
+        }
+    }
+    Ok(frame)
+}
+
+/// Reassembles frames from the chunks read from a channel. Corrupt frames are logged and dropped.
+#[derive(Debug)]
+pub struct Deframer {
+    framing: Framing,
+    /// Channel name for log messages
+    name: String,
+    buffer: Vec<u8>,
+    /// The last SLIP byte was an escape
+    escaped: bool,
+    /// The SLIP frame being received had a bad escape sequence
+    corrupt: bool,
+    /// Data is being skipped until the next delimiter after an oversized frame
+    skipping: bool,
+}
+
+impl Deframer {
+    pub fn new(framing: Framing, name: &str) -> Self {
+        Self {
+            framing,
+            name: name.to_string(),
+            buffer: vec![],
+            escaped: false,
+            corrupt: false,
+            skipping: false,
+        }
+    }
+
+    fn report(&self, reason: &str) {
+        warn!(
+            "Dropping corrupt {:?} frame on {}: {}",
+            self.framing, self.name, reason
+        );
+    }
+
+    /// Feeds in data from the channel, calling `frame` for every complete frame
+    pub fn push(
+        &mut self,
+        data: &[u8],
+        mut frame: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match self.framing {
+            Framing::Cobs => self.push_cobs(data, &mut frame),
+            Framing::Slip => self.push_slip(data, &mut frame),
+            _ => self.push_length_prefixed(data, &mut frame),
+        }
+    }
+
+    fn push_cobs(
+        &mut self,
+        data: &[u8],
+        frame: &mut impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for &byte in data {
+            if byte != 0 {
+                if !self.skipping {
+                    self.buffer.push(byte);
+                }
+                if self.buffer.len() > MAX_FRAME_SIZE {
+                    self.report("too long");
+                    self.buffer.clear();
+                    self.skipping = true;
+                }
+                continue;
+            }
+            self.skipping = false;
+            if self.buffer.is_empty() {
+                continue;
+            }
+            match cobs_decode(&self.buffer) {
+                Ok(decoded) => frame(&decoded)?,
+                Err(reason) => self.report(reason),
+            }
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn push_slip(
+        &mut self,
+        data: &[u8],
+        frame: &mut impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for &byte in data {
+            if byte == SLIP_END {
+                if self.corrupt && !self.skipping {
+                    self.report("invalid escape sequence");
+                } else if !self.skipping && !self.buffer.is_empty() {
+                    frame(&self.buffer)?;
+                }
+                self.buffer.clear();
+                self.escaped = false;
+                self.corrupt = false;
+                self.skipping = false;
+                continue;
+            }
+            if self.skipping {
+                continue;
+            }
+            if self.escaped {
+                self.escaped = false;
+                match byte {
+                    SLIP_ESC_END => self.buffer.push(SLIP_END),
+                    SLIP_ESC_ESC => self.buffer.push(SLIP_ESC),
+                    _ => self.corrupt = true,
+                }
+            } else if byte == SLIP_ESC {
+                self.escaped = true;
+            } else {
+                self.buffer.push(byte);
+            }
+            if self.buffer.len() > MAX_FRAME_SIZE {
+                self.report("too long");
+                self.buffer.clear();
+                self.skipping = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_length_prefixed(
+        &mut self,
+        data: &[u8],
+        frame: &mut impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let prefix = self
+            .framing
+            .prefix_len()
+            .expect("framing has a length prefix");
+        self.buffer.extend_from_slice(data);
+        let mut start = 0;
+        while self.buffer.len() - start >= prefix {
+            let len = self.framing.frame_len(&self.buffer[start..start + prefix]);
+            if len > MAX_FRAME_SIZE {
+                // There's no delimiter to resynchronise on, so drop everything we have and hope
+                // the next read starts on a frame
+                self.report("length is too big");
+                start = self.buffer.len();
+                break;
+            }
+            let end = start + prefix + len;
+            if end > self.buffer.len() {
+                break;
+            }
+            frame(&self.buffer[start + prefix..end])?;
+            start = end;
+        }
+        self.buffer.drain(..start);
+        Ok(())
+    }
+
+    /// Drops any partly received frame, used when data may have been lost
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.escaped = false;
+        self.corrupt = false;
+        // Wait for a delimiter so we don't decode the tail of a frame as a whole one
+        self.skipping = matches!(self.framing, Framing::Cobs | Framing::Slip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes each chunk in turn, returning every frame produced
+    fn frames(framing: Framing, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut deframer = Deframer::new(framing, "test");
+        let mut frames = vec![];
+        for chunk in chunks {
+            deframer
+                .push(chunk, |x| {
+                    frames.push(x.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+        }
+        frames
+    }
+
+    #[test]
+    fn cobs_zeros() {
+        let frames = frames(Framing::Cobs, &[&[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]]);
+        assert_eq!(frames, vec![vec![0x11, 0x22, 0x00, 0x33]]);
+        let frames = self::frames(Framing::Cobs, &[&[0x01, 0x01, 0x00]]);
+        assert_eq!(frames, vec![vec![0x00]]);
+    }
+
+    #[test]
+    fn cobs_full_block() {
+        // 254 non-zero bytes fill a block, which isn't followed by an implicit zero
+        let data: Vec<u8> = (1..=254).collect();
+        let mut encoded = vec![0xff];
+        encoded.extend_from_slice(&data);
+        encoded.extend_from_slice(&[0x01, 0x00]);
+        assert_eq!(frames(Framing::Cobs, &[&encoded]), vec![data.clone()]);
+
+        let mut longer = data.clone();
+        longer.push(0x42);
+        let mut encoded = vec![0xff];
+        encoded.extend_from_slice(&data);
+        encoded.extend_from_slice(&[0x02, 0x42, 0x00]);
+        assert_eq!(frames(Framing::Cobs, &[&encoded]), vec![longer]);
+    }
+
+    #[test]
+    fn cobs_split_across_pushes() {
+        let frames = frames(
+            Framing::Cobs,
+            &[&[0x00, 0x03, 0x11], &[0x22, 0x00, 0x02], &[0x33, 0x00]],
+        );
+        assert_eq!(frames, vec![vec![0x11, 0x22], vec![0x33]]);
+    }
+
+    #[test]
+    fn cobs_corrupt_frame_dropped() {
+        // The first block claims more bytes than the frame has
+        let frames = frames(Framing::Cobs, &[&[0x05, 0x11, 0x00, 0x02, 0x22, 0x00]]);
+        assert_eq!(frames, vec![vec![0x22]]);
+    }
+
+    #[test]
+    fn slip_escapes() {
+        let frames = frames(
+            Framing::Slip,
+            &[&[
+                0x01,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                0x02,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                SLIP_END,
+            ]],
+        );
+        assert_eq!(frames, vec![vec![0x01, SLIP_END, 0x02, SLIP_ESC]]);
+    }
+
+    #[test]
+    fn slip_escape_split_across_pushes() {
+        let frames = frames(
+            Framing::Slip,
+            &[&[SLIP_END, 0x01, SLIP_ESC], &[SLIP_ESC_END, SLIP_END]],
+        );
+        assert_eq!(frames, vec![vec![0x01, SLIP_END]]);
+    }
+
+    #[test]
+    fn slip_bad_escape_dropped() {
+        let frames = frames(
+            Framing::Slip,
+            &[&[0x01, SLIP_ESC, 0x02, SLIP_END, 0x03, SLIP_END]],
+        );
+        assert_eq!(frames, vec![vec![0x03]]);
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let frames = self::frames(Framing::U16Le, &[&[0x02, 0x00, 0xaa, 0xbb, 0x00, 0x00]]);
+        assert_eq!(frames, vec![vec![0xaa, 0xbb], vec![]]);
+        let frames = self::frames(Framing::U32Be, &[&[0x00, 0x00, 0x00, 0x01, 0xcc]]);
+        assert_eq!(frames, vec![vec![0xcc]]);
+    }
+
+    #[test]
+    fn length_prefixed_split_across_pushes() {
+        let frames = frames(
+            Framing::U16Be,
+            &[&[0x00], &[0x03, 0x01, 0x02], &[0x03, 0x00, 0x01], &[0x04]],
+        );
+        assert_eq!(frames, vec![vec![0x01, 0x02, 0x03], vec![0x04]]);
+    }
+
+    #[test]
+    fn oversized_length_dropped() {
+        let mut corrupt = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes().to_vec();
+        corrupt.extend_from_slice(&[0x01, 0x02]);
+        // Everything buffered is dropped and the next read is assumed to start on a frame
+        let frames = frames(Framing::U32Le, &[&corrupt, &[0x01, 0x00, 0x00, 0x00, 0x05]]);
+        assert_eq!(frames, vec![vec![0x05]]);
+    }
+
+    #[test]
+    fn reset_waits_for_delimiter() {
+        let mut deframer = Deframer::new(Framing::Cobs, "test");
+        let mut frames = vec![];
+        let mut collect = |x: &[u8]| {
+            frames.push(x.to_vec());
+            Ok(())
+        };
+        deframer.push(&[0x03, 0x11], &mut collect).unwrap();
+        deframer.reset();
+        // The tail of the interrupted frame is skipped
+        deframer
+            .push(&[0x22, 0x00, 0x02, 0x33, 0x00], &mut collect)
+            .unwrap();
+        assert_eq!(frames, vec![vec![0x33]]);
+    }
+}
@@ -1,27 +1,73 @@
-use probe_rs::Probe;
-use probe_rs_rtt::{Rtt, ScanRegion, UpChannel};
+use crate::decode::{Decoder, DefmtTable, Format};
+use crate::down::{ChannelSource, DownConfig};
+use crate::mode::Mode;
+use crate::output::PathContext;
+use crate::overflow::OverflowDetector;
+use crate::poll::PollSchedule;
+use crate::pty::{Pty, PtyConfig};
+use crate::reconnect::Backoff;
+use crate::runner::{LineMatcher, Outcome, Pattern, ERROR_EXIT_CODE};
+use crate::selector::ProbeSelector;
+use crate::sink::{match_decoder, Output, SinkConfig};
+use crate::stats::{ChannelStats, Summary};
+use probe_rs::config::MemoryRegion;
+use probe_rs::flashing::{self, download_file_with_options, DownloadOptions};
+use probe_rs::{Core, MemoryInterface, Probe, Session};
+use probe_rs_rtt::{DownChannel, Rtt, ScanRegion, UpChannel};
 use serde::Deserialize;
 use std::fs;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use tracing::{debug, error, info, trace, warn};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter};
 
+mod command;
+mod compression;
+mod coverage;
+mod decode;
+mod down;
+mod framing;
+mod mode;
+mod output;
+mod overflow;
+mod poll;
+mod pty;
+mod queue;
+mod reconnect;
+mod records;
+mod rotation;
+mod runner;
+mod selector;
+mod sink;
+mod stats;
+mod tcp;
+mod timestamp;
+
+/// How long to wait for freshly flashed firmware to set up its RTT control block
+const FLASH_ATTACH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the RTT control block is checked when reconnecting is enabled
+const CONTROL_BLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Without `--reconnect` this many channel errors in a row means the probe or target is gone
+const MAX_SEQUENTIAL_ERRORS: u32 = 20;
+
 #[derive(Debug, Clone, StructOpt)]
 pub struct Args {
-    /// Index of the core to attach to
+    /// Index of the core to attach to, unless several cores are configured with `cores`
     #[structopt(long, default_value = "0")]
     core: usize,
     /// name of the chip
     #[structopt(long)]
     chip: String,
-    /// Index of the probe to use
-    #[structopt(long, default_value = "0")]
-    probe: usize,
+    /// Probe to use, either a serial number, VID:PID, VID:PID:SERIAL or its index in the probe
+    /// list. Defaults to the first probe
+    #[structopt(long)]
+    probe: Option<ProbeSelector>,
     /// A toml file specifying the configuration
     #[structopt(short, long)]
     config: Option<PathBuf>,
@@ -32,36 +78,370 @@ pub struct Args {
     /// the localtion
     #[structopt(long)]
     binary: Option<PathBuf>,
+    /// Flash the ELF given by `--binary` to the target and reset it before logging
+    #[structopt(long)]
+    flash: bool,
+    /// Verify the flash contents after flashing
+    #[structopt(long, requires = "flash")]
+    verify: bool,
+    /// Streams a file, FIFO or stdin (`-`) into a down channel, given as CHANNEL=SOURCE. Can be
+    /// passed multiple times
+    #[structopt(long)]
+    down: Vec<DownConfig>,
+    /// Log every up channel on the target, not just the configured ones. This is the default when
+    /// no channels are configured
+    #[structopt(long)]
+    all_channels: bool,
+    /// Directory to write automatically discovered channels to
+    #[structopt(long)]
+    output_dir: Option<PathBuf>,
+    /// Reconnect to the target if the probe is disconnected or the target resets
+    #[structopt(long)]
+    reconnect: bool,
+    /// Milliseconds to wait before the first reconnection attempt, doubling after each failure
+    #[structopt(long, default_value = "500")]
+    reconnect_delay: u64,
+    /// Longest wait in milliseconds between reconnection attempts
+    #[structopt(long, default_value = "10000")]
+    reconnect_max_delay: u64,
+    /// Give up after this many consecutive failed reconnection attempts
+    #[structopt(long)]
+    reconnect_attempts: Option<usize>,
+    /// Exit with the timeout code if no pass or fail pattern has matched after this many seconds
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// Exit with the timeout code if no data is received for this many seconds
+    #[structopt(long)]
+    idle_timeout: Option<u64>,
+    /// Stop logging after this many seconds
+    #[structopt(long)]
+    duration: Option<u64>,
+    /// Stop logging once this many bytes have been received across all channels
+    #[structopt(long)]
+    max_bytes: Option<u64>,
+    /// Stop logging once any channel has received this many bytes
+    #[structopt(long)]
+    max_channel_bytes: Option<u64>,
+    /// Stop logging once no data has been received for this many seconds
+    #[structopt(long)]
+    max_idle: Option<u64>,
+    /// Shortest time in milliseconds between reads of a busy channel
+    #[structopt(long, default_value = "1")]
+    poll_min: u64,
+    /// Longest time in milliseconds between reads of an idle channel
+    #[structopt(long, default_value = "100")]
+    poll_max: u64,
+    /// Log each channel's statistics every this many seconds, 0 turns this off
+    #[structopt(long, default_value = "60")]
+    stats_interval: u64,
+    /// Write a JSON summary of the capture and each channel's statistics here when it ends
+    #[structopt(long)]
+    summary: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
-    #[serde(rename = "rtt_file")]
+    #[serde(rename = "rtt_file", default)]
     rtt_config: RttConfig,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct RttConfig {
+    /// Probe to use, overridden by `--probe`
+    probe: Option<ProbeSelector>,
+    /// Channels to log, if this is empty every up channel is logged
+    #[serde(default)]
     channels: Vec<Channel>,
+    /// Directory to write automatically discovered channels to
+    output_dir: Option<PathBuf>,
+    /// Sources to feed into down channels
+    #[serde(default)]
+    down: Vec<DownConfig>,
+    /// Cores with their own RTT control block, used instead of `channels` and `down` to log
+    /// several cores at once
+    #[serde(default)]
+    cores: Vec<CoreConfig>,
 }
 
+/// The RTT channels of one core
 #[derive(Debug, Clone, Deserialize)]
+pub struct CoreConfig {
+    /// Index of the core
+    core: usize,
+    /// ELF running on the core, used to find its control block and defmt table. Defaults to
+    /// `--binary`
+    binary: Option<PathBuf>,
+    /// Channels to log, if this is empty every up channel on the core is logged
+    #[serde(default)]
+    channels: Vec<Channel>,
+    /// Sources to feed into down channels
+    #[serde(default)]
+    down: Vec<DownConfig>,
+}
+
+impl Config {
+    /// The cores to log, when `cores` isn't used this is the `--core` with the top level channels
+    fn cores(&self, args: &Args) -> Vec<CoreConfig> {
+        let mut cores = if self.rtt_config.cores.is_empty() {
+            vec![CoreConfig {
+                core: args.core,
+                binary: None,
+                channels: self.rtt_config.channels.clone(),
+                down: self.rtt_config.down.clone(),
+            }]
+        } else {
+            self.rtt_config.cores.clone()
+        };
+        for core in cores.iter_mut() {
+            if core.binary.is_none() {
+                core.binary = args.binary.clone();
+            }
+            // Down channels given on the command line go to `--core`
+            if core.core == args.core {
+                core.down.extend(args.down.iter().cloned());
+            }
+        }
+        cores
+    }
+}
+
+/// What's known about the firmware running on a core
+struct Firmware<'a> {
+    core: usize,
+    binary: Option<&'a Path>,
+    defmt: Option<&'static DefmtTable>,
+}
+
+impl<'a> Firmware<'a> {
+    /// Loads the defmt table from the core's ELF if any of its channels need it
+    fn load(config: &'a CoreConfig) -> Self {
+        let uses_defmt = config
+            .channels
+            .iter()
+            .flat_map(Channel::outputs)
+            .any(|x| x.format == Format::Defmt);
+        let defmt = match config.binary.as_ref() {
+            Some(binary) if uses_defmt => match DefmtTable::load(binary) {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    warn!("Failed to load defmt table: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        Self {
+            core: config.core,
+            binary: config.binary.as_deref(),
+            defmt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Channel {
-    up: usize,
+    /// Index of the up channel, if this isn't set the channel is found by `name` instead
+    up: Option<usize>,
     name: String,
-    path: PathBuf,
+    /// The channel's main output, this is optional if `sinks` is used instead
+    #[serde(flatten)]
+    output: SinkConfig,
+    /// Further outputs for the same channel, each with its own format and destination
+    #[serde(default)]
+    sinks: Vec<SinkConfig>,
+    /// Pair the channel with a down channel and expose both as a pseudo-terminal
+    pty: Option<PtyConfig>,
+    /// Mode to put the channel in while it's logged, the target's own mode is restored on exit
+    mode: Option<Mode>,
+    /// Stop and exit successfully when a line of the channel matches one of these
+    #[serde(default)]
+    pass: Vec<Pattern>,
+    /// Stop and exit with the failure code when a line of the channel matches one of these
+    #[serde(default)]
+    fail: Vec<Pattern>,
+}
+
+impl Channel {
+    /// Every output configured for the channel
+    fn outputs(&self) -> impl Iterator<Item = &SinkConfig> {
+        Some(&self.output)
+            .filter(|x| x.has_destination())
+            .into_iter()
+            .chain(self.sinks.iter())
+    }
 }
 
 #[derive(Debug)]
 pub struct ChannelSink {
+    /// Core whose RTT control block the channel belongs to
+    core: usize,
     channel: UpChannel,
+    /// The config entry used to find `channel` again after reconnecting
+    config: Channel,
     name: String,
-    file: fs::File,
+    outputs: Vec<Output>,
+    /// Kept alive while the channel is being logged
+    pty: Option<Pty>,
+    /// Looks for the test result when the channel has pass or fail patterns
+    matcher: Option<LineMatcher>,
+    /// Turns the channel data into the text `matcher` looks at
+    match_decoder: Decoder,
+    stats: ChannelStats,
+    /// When to next read the channel
+    schedule: PollSchedule,
+    /// Set up once the control block address is known
+    overflow: Option<OverflowDetector>,
+    /// The channel's mode before `mode` was applied, to put back when logging stops
+    original_mode: Option<Mode>,
     working: bool,
 }
 
+impl ChannelSink {
+    fn new(
+        channel: UpChannel,
+        config: Channel,
+        firmware: &Firmware,
+        start: Instant,
+        paths: &PathContext,
+        schedule: PollSchedule,
+    ) -> std::io::Result<Self> {
+        let outputs = config
+            .outputs()
+            .map(|x| {
+                let index = channel.number();
+                let (defmt, binary) = (firmware.defmt, firmware.binary);
+                Output::new(x, &config.name, index, defmt, start, paths, binary)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let matcher = LineMatcher::new(&config.pass, &config.fail).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid pattern for {}: {}", config.name, e),
+            )
+        })?;
+        let defmt_output = config.outputs().find(|x| x.format == Format::Defmt);
+        let match_decoder = match_decoder(defmt_output, firmware.defmt);
+        if outputs.is_empty() && config.pty.is_none() && matcher.is_none() {
+            warn!("{} has no outputs configured", config.name);
+        }
+        Ok(Self {
+            stats: ChannelStats::new(&config.name, firmware.core, channel.number()),
+            core: firmware.core,
+            channel,
+            name: config.name.clone(),
+            working: !outputs.is_empty() || matcher.is_some(),
+            outputs,
+            pty: None,
+            matcher,
+            match_decoder,
+            schedule,
+            overflow: None,
+            original_mode: None,
+            config,
+        })
+    }
+
+    /// The channel is still worth reading if something is looking at its data
+    fn update_working(&mut self) {
+        self.working = self.matcher.is_some() || self.outputs.iter().any(|x| x.working);
+    }
+
+    /// Writes data read from the channel to every working output, returning the test result if
+    /// it contained a pass or fail marker
+    fn write(&mut self, data: &[u8]) -> Option<Outcome> {
+        for output in self.outputs.iter_mut().filter(|x| x.working) {
+            output.write(data);
+        }
+        self.update_working();
+        let matcher = self.matcher.as_mut()?;
+        let mut text = vec![];
+        if let Err(e) = self.match_decoder.write(data, &mut text) {
+            warn!("Couldn't decode {} to look for patterns: {}", self.name, e);
+            return None;
+        }
+        matcher.check(&text)
+    }
+
+    /// Flushes outputs holding compressed data for longer than their flush interval
+    fn flush_if_due(&mut self) {
+        for output in self.outputs.iter_mut().filter(|x| x.working) {
+            output.flush_if_due();
+        }
+        self.update_working();
+    }
+
+    /// Writes out anything still buffered and finishes the files, called when the capture ends
+    fn finish(&mut self) {
+        for output in &mut self.outputs {
+            if let Err(e) = output.finish() {
+                error!("Failed to finish {}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// Marks a point where the target may have dropped data in the outputs that can take a marker
+    fn mark_loss(&mut self, message: &str) {
+        let marker = format!(
+            "possible data loss at {}: {}",
+            chrono::Local::now().to_rfc3339(),
+            message
+        );
+        for output in self.outputs.iter_mut().filter(|x| x.working) {
+            output.mark_gap(&marker, false);
+        }
+        self.update_working();
+    }
+
+    /// Swaps in the channel from a fresh RTT attach and marks the gap in the output
+    fn rebind(&mut self, rtt: &mut Rtt) -> Result<(), String> {
+        self.channel = take_up_channel(rtt, &self.config)?;
+        let marker = format!(
+            "connection lost, reconnected at {}",
+            chrono::Local::now().to_rfc3339()
+        );
+        self.match_decoder.reset();
+        for output in self.outputs.iter_mut().filter(|x| x.working) {
+            output.mark_gap(&marker, true);
+        }
+        self.update_working();
+        Ok(())
+    }
+}
+
+/// Everything that carries on across reconnections for the whole capture
+struct Capture {
+    /// When the logger started, monotonic timestamps count from here
+    start: Instant,
+    paths: PathContext,
+    sinks: Vec<ChannelSink>,
+    sources: Vec<ChannelSource>,
+    /// When data was last received from any channel
+    last_data: Instant,
+    /// Bytes read from all channels over the whole capture
+    received: u64,
+    /// When the channel statistics were last logged
+    last_stats: Instant,
+    /// Whether `--flash` has been done, so retries don't flash again
+    flashed: bool,
+    /// Cores whose sinks and sources have been created, later sessions rebind them
+    ready: Vec<usize>,
+}
+
+/// How a session with the target ended
+enum SessionEnd {
+    /// We were asked to close
+    Closed,
+    /// The probe, core or RTT control block went away and we should try to reconnect
+    Lost(Box<dyn std::error::Error>),
+    /// A pass or fail pattern matched or a timeout was hit
+    Finished(Outcome),
+    /// The outputs couldn't be created, reconnecting won't help
+    Failed(Box<dyn std::error::Error>),
+}
+
 fn setup_tracing() {
-    let fmt_layer = fmt::layer().with_target(false);
+    // Logs go to stderr so they don't mix with channels written to stdout
+    let fmt_layer = fmt::layer().with_target(false).with_writer(std::io::stderr);
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("rtt_file_logger=trace"))
         .unwrap();
@@ -91,37 +471,70 @@ pub fn get_rtt_symbol<T: Read + Seek>(file: &mut T) -> Option<u64> {
     None
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    setup_tracing();
-
-    let args = Args::from_args();
-    // Get channels dump to file
-    let config_file = args.config.unwrap_or(PathBuf::from("Embed.toml"));
-
-    info!("Reading configuration file");
-    let config_toml = fs::read_to_string(config_file)?;
-    let config: Config = toml::from_str(&config_toml)?;
-
-    info!("Getting probe: {}", args.probe);
-    let probe = Probe::list_all()[args.probe].open()?;
-    info!("Attaching to chip: {}", args.chip);
-    let mut session = if args.reset {
-        probe.attach_under_reset(&args.chip)?
-    } else {
-        probe.attach(&args.chip)?
+/// Takes the up channel a config entry refers to, either by index or by the name the target
+/// reports in the RTT control block.
+fn take_up_channel(rtt: &mut Rtt, channel: &Channel) -> Result<UpChannel, String> {
+    let index = match channel.up {
+        Some(index) => index,
+        None => rtt
+            .up_channels()
+            .iter()
+            .find(|x| x.name() == Some(channel.name.as_str()))
+            .map(|x| x.number())
+            .ok_or_else(|| {
+                format!(
+                    "No up channel named '{}' found on the target (or it's already in use)",
+                    channel.name
+                )
+            })?,
     };
-    let memory_map = session.target().memory_map.clone();
-
-    debug!("Memory map: {:?}", memory_map);
+    let up = rtt.up_channels().take(index).ok_or_else(|| {
+        format!(
+            "Up channel {} ('{}') not found on the target (or it's already in use)",
+            index, channel.name
+        )
+    })?;
+    match up.name() {
+        Some(name) if name != channel.name => warn!(
+            "Up channel {} is configured as '{}' but the target calls it '{}'",
+            index, channel.name, name
+        ),
+        None => debug!("Up channel {} has no name on the target", index),
+        _ => {}
+    }
+    Ok(up)
+}
 
-    info!("Getting core: {}", args.core);
-    let mut core = session.core(args.core)?;
+/// Output file for a channel that wasn't in the config, named after the channel and its index
+fn discovered_channel_path(dir: &Path, channel: &UpChannel) -> PathBuf {
+    let name = match channel.name() {
+        Some(name) if !name.is_empty() => name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+        _ => "channel".to_string(),
+    };
+    dir.join(format!("{}_{}.log", name, channel.number()))
+}
 
+/// Attaches to RTT by scanning memory for the control block, falling back to the location of the
+/// `_SEGGER_RTT` symbol in `binary`
+fn attach_rtt(
+    core: &mut Core,
+    memory_map: &[MemoryRegion],
+    binary: Option<&PathBuf>,
+) -> Result<Rtt, Box<dyn std::error::Error>> {
     info!("Attaching via RTT");
-    let rtt = Rtt::attach(&mut core, &memory_map);
+    let rtt = Rtt::attach(core, memory_map);
 
-    let mut rtt = match (rtt, args.binary.as_ref()) {
-        (Ok(r), _) => r,
+    match (rtt, binary) {
+        (Ok(r), _) => Ok(r),
         (Err(_), Some(bin)) => {
             warn!("Failed to attach to RTT");
             info!(
@@ -130,35 +543,625 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             );
             let mut file = fs::File::open(bin)?;
             if let Some(addr) = get_rtt_symbol(&mut file) {
-                Rtt::attach_region(&mut core, &memory_map, &ScanRegion::Exact(addr as u32))?
+                Ok(Rtt::attach_region(
+                    core,
+                    memory_map,
+                    &ScanRegion::Exact(addr as u32),
+                )?)
             } else {
-                panic!("Unable to attach RTT");
+                Err("Unable to attach RTT".into())
             }
         }
         (Err(e), None) => {
             error!("Failed to connect");
-            panic!("{}", e);
+            Err(e.into())
         }
+    }
+}
+
+/// Checks the control block still starts with the RTT id, if the target resets it is cleared or
+/// moved
+fn control_block_valid(core: &mut Core, ptr: u32) -> Result<bool, probe_rs::Error> {
+    let mut id = [0u8; 10];
+    core.read_8(ptr, &mut id)?;
+    Ok(&id == b"SEGGER RTT")
+}
+
+/// The probe picked on the command line or in the config, defaulting to the first one
+fn probe_selector(args: &Args, config: &Config) -> ProbeSelector {
+    args.probe
+        .clone()
+        .or_else(|| config.rtt_config.probe.clone())
+        .unwrap_or_default()
+}
+
+/// Catches mistakes in the arguments and config before connecting, as `--reconnect` would
+/// otherwise retry them forever
+fn check_config(args: &Args, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if args.flash && args.binary.is_none() {
+        return Err("--flash needs the ELF file passed via --binary".into());
+    }
+    for core in config.cores(args) {
+        for channel in &core.channels {
+            LineMatcher::new(&channel.pass, &channel.fail)
+                .map_err(|e| format!("invalid pattern for {}: {}", channel.name, e))?;
+        }
+    }
+    probe_selector(args, config).select(&Probe::list_all())?;
+    Ok(())
+}
+
+/// Connects to the target and logs until we're asked to close or the connection is lost. The
+/// first time a core's channels are found its sinks and sources are created, after that the
+/// existing ones are rebound to the channels of the new RTT attach.
+fn run_session(
+    args: &Args,
+    config: &Config,
+    capture: &mut Capture,
+    running: &AtomicBool,
+) -> Result<SessionEnd, Box<dyn std::error::Error>> {
+    let selector = probe_selector(args, config);
+    info!("Getting probe: {}", selector);
+    let probes = Probe::list_all();
+    let probe = selector.select(&probes)?.open()?;
+    info!("Attaching to chip: {}", args.chip);
+    let mut session = if args.reset {
+        probe.attach_under_reset(&args.chip)?
+    } else {
+        probe.attach(&args.chip)?
     };
+    let memory_map = session.target().memory_map.clone();
+
+    debug!("Memory map: {:?}", memory_map);
+
+    let flash = args.flash && !capture.flashed;
+    if flash {
+        let binary = args
+            .binary
+            .as_ref()
+            .ok_or("--flash needs the ELF file passed via --binary")?;
+        info!("Flashing {}", binary.display());
+        let mut options = DownloadOptions::new();
+        options.verify = args.verify;
+        download_file_with_options(&mut session, binary, flashing::Format::Elf, options)?;
+        info!("Flashing complete");
+        capture.flashed = true;
+    }
+
+    let cores = config.cores(args);
+    let mut attached = vec![];
+    for core_config in &cores {
+        let rtt = attach_core(&mut session, &memory_map, core_config, flash)?;
+        attached.push(AttachedCore {
+            index: core_config.core,
+            rtt,
+        });
+    }
+
+    // Every core's channels are found before anything is created, so a target that isn't ready
+    // yet can be retried without leaving half the outputs behind
+    let multicore = cores.len() > 1;
+    let mut found = vec![];
+    for (core_config, core) in cores.iter().zip(attached.iter_mut()) {
+        if !capture.ready.contains(&core.index) {
+            let channels = find_channels(args, config, core_config, &mut core.rtt, multicore)?;
+            found.push((core_config, channels));
+            continue;
+        }
+        for sink in capture.sinks.iter_mut().filter(|x| x.core == core.index) {
+            sink.rebind(&mut core.rtt)?;
+        }
+        for source in capture.sources.iter_mut().filter(|x| x.core == core.index) {
+            let index = source.channel.number();
+            source.channel = core
+                .rtt
+                .down_channels()
+                .take(index)
+                .ok_or_else(|| format!("Down channel {} missing after reconnecting", index))?;
+        }
+        info!("Reconnected to core {}", core.index);
+    }
+
+    let schedule = PollSchedule::new(
+        Duration::from_millis(args.poll_min),
+        Duration::from_millis(args.poll_max),
+    );
+    for (core_config, channels) in found {
+        if let Err(e) = setup_core(core_config, channels, capture, &schedule) {
+            return Ok(SessionEnd::Failed(e));
+        }
+        capture.ready.push(core_config.core);
+    }
+    debug!("Got sinks: {:?}", capture.sinks);
+    debug!("Got sources: {:?}", capture.sources);
 
-    let mut sinks: Vec<ChannelSink> = config
-        .rtt_config
-        .channels
-        .iter()
-        .map(|x| {
-            let channel = rtt.up_channels().take(x.up).expect("Channel missing");
-            ChannelSink {
-                channel,
-                name: x.name.clone(),
-                file: fs::File::create(&x.path).expect("Couldn't create output file"),
-                working: true,
+    for attached in &attached {
+        let mut core = session.core(attached.index)?;
+        for sink in capture
+            .sinks
+            .iter_mut()
+            .filter(|x| x.core == attached.index)
+        {
+            sink.overflow = Some(OverflowDetector::new(
+                attached.rtt.ptr(),
+                sink.channel.number(),
+                sink.channel.buffer_size(),
+            ));
+            if let Some(mode) = sink.config.mode {
+                let current = sink.channel.mode(&mut core)?;
+                // After reconnecting the channel may still be in our mode, keep the first one seen
+                sink.original_mode.get_or_insert(current.into());
+                sink.channel.set_mode(&mut core, mode.into())?;
+                info!("Set {} to {:?}", sink.name, mode);
             }
+        }
+    }
+
+    let end = poll(args, capture, &mut session, &attached, running);
+    if matches!(end, Ok(SessionEnd::Closed | SessionEnd::Finished(_))) {
+        restore_modes(capture, &mut session);
+    }
+    end
+}
+
+/// A core we've attached to RTT on for this session
+struct AttachedCore {
+    index: usize,
+    rtt: Rtt,
+}
+
+/// Attaches to RTT on a core. Freshly flashed firmware is reset and given a moment to set up its
+/// control block
+fn attach_core(
+    session: &mut Session,
+    memory_map: &[MemoryRegion],
+    config: &CoreConfig,
+    flashed: bool,
+) -> Result<Rtt, Box<dyn std::error::Error>> {
+    info!("Getting core: {}", config.core);
+    let mut core = session.core(config.core)?;
+    if !flashed {
+        return attach_rtt(&mut core, memory_map, config.binary.as_ref());
+    }
+    // The core is already running the new firmware so retry until it's set up RTT
+    info!("Resetting core");
+    core.reset()?;
+    let start = Instant::now();
+    loop {
+        match attach_rtt(&mut core, memory_map, config.binary.as_ref()) {
+            Ok(r) => return Ok(r),
+            Err(e) if start.elapsed() < FLASH_ATTACH_TIMEOUT => {
+                debug!("RTT not ready yet: {}", e);
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The RTT channels used by a core's sinks and sources
+struct CoreChannels {
+    /// Up channels with their config and the down channel for the channel's terminal, if any
+    up: Vec<(UpChannel, Channel, Option<DownChannel>)>,
+    down: Vec<(DownChannel, DownConfig)>,
+    /// Where discovered channels are written
+    discovered_dir: Option<PathBuf>,
+}
+
+/// Finds the channels for a core's config on the target. Nothing is created yet, so this can be
+/// retried if the target hasn't set up its channels
+fn find_channels(
+    args: &Args,
+    config: &Config,
+    core_config: &CoreConfig,
+    rtt: &mut Rtt,
+    multicore: bool,
+) -> Result<CoreChannels, Box<dyn std::error::Error>> {
+    let mut up = vec![];
+    for x in &core_config.channels {
+        up.push((take_up_channel(rtt, x)?, x.clone()));
+    }
+
+    let mut discovered_dir = None;
+    if args.all_channels || core_config.channels.is_empty() {
+        let output_dir = args
+            .output_dir
+            .as_ref()
+            .or(config.rtt_config.output_dir.as_ref())
+            .map(PathBuf::as_path)
+            .unwrap_or_else(|| Path::new("."));
+        // Each core gets its own directory so channels with the same name don't clash
+        let output_dir = if multicore {
+            output_dir.join(format!("core{}", core_config.core))
+        } else {
+            output_dir.to_path_buf()
+        };
+        for channel in rtt.up_channels().drain() {
+            let path = discovered_channel_path(&output_dir, &channel);
+            info!(
+                "Discovered up channel {} on core {}",
+                channel.number(),
+                core_config.core
+            );
+            let name = channel
+                .name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("up{}", channel.number()));
+            let config = Channel {
+                up: Some(channel.number()),
+                name,
+                output: SinkConfig {
+                    path: Some(path),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            up.push((channel, config));
+        }
+        discovered_dir = Some(output_dir);
+    }
+
+    let mut down = vec![];
+    for x in &core_config.down {
+        let channel = rtt.down_channels().take(x.down).ok_or_else(|| {
+            format!(
+                "Down channel {} for {} ({}) not found on core {} (or it's already in use)",
+                x.down, x.name, x.source, core_config.core
+            )
+        })?;
+        down.push((channel, x.clone()));
+    }
+
+    let up = up
+        .into_iter()
+        .map(|(channel, config)| {
+            let terminal = match config.pty.as_ref() {
+                Some(pty) => Some(rtt.down_channels().take(pty.down).ok_or_else(|| {
+                    format!(
+                        "Down channel {} for the {} terminal not found on the target (or it's already in use)",
+                        pty.down, config.name
+                    )
+                })?),
+                None => None,
+            };
+            Ok((channel, config, terminal))
         })
-        .collect();
+        .collect::<Result<_, String>>()?;
+
+    Ok(CoreChannels {
+        up,
+        down,
+        discovered_dir,
+    })
+}
+
+/// Creates the sinks and sources for a core's channels. They're only added to the capture once
+/// they've all been created
+fn setup_core(
+    core_config: &CoreConfig,
+    channels: CoreChannels,
+    capture: &mut Capture,
+    schedule: &PollSchedule,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let firmware = Firmware::load(core_config);
+    if let Some(dir) = channels.discovered_dir.as_ref() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut sources = channels
+        .down
+        .into_iter()
+        .map(|(channel, x)| ChannelSource::new(core_config.core, channel, x.name, x.source))
+        .collect::<Vec<_>>();
+
+    let mut sinks = vec![];
+    for (channel, config, terminal) in channels.up {
+        let name = config.name.clone();
+        let mut sink = ChannelSink::new(
+            channel,
+            config,
+            &firmware,
+            capture.start,
+            &capture.paths,
+            schedule.clone(),
+        )
+        .map_err(|e| format!("Couldn't create output file for {}: {}", name, e))?;
+        if let Some(down) = terminal {
+            let link = sink.config.pty.as_ref().and_then(|x| x.link.as_deref());
+            let pty = Pty::open(link)?;
+            info!("Terminal for {} is {}", sink.name, pty.path().display());
+            sink.outputs.push(Output::pty(pty.writer()?, pty.path()));
+            sink.working = true;
+            sources.push(ChannelSource::from_reader(
+                core_config.core,
+                down,
+                sink.name.clone(),
+                pty.path().display().to_string(),
+                pty.reader()?,
+            ));
+            sink.pty = Some(pty);
+        }
+        sinks.push(sink);
+    }
 
-    debug!("Got sinks: {:?}", sinks);
+    capture.sinks.extend(sinks);
+    capture.sources.extend(sources);
+    Ok(())
+}
+
+/// Puts back the modes the channels had before we changed them, so a target left in
+/// `BlockIfFull` doesn't stall once nothing is reading it
+fn restore_modes(capture: &Capture, session: &mut Session) {
+    for sink in &capture.sinks {
+        if let Some(mode) = sink.original_mode {
+            let result = session
+                .core(sink.core)
+                .map_err(probe_rs_rtt::Error::from)
+                .and_then(|mut core| sink.channel.set_mode(&mut core, mode.into()));
+            match result {
+                Ok(()) => info!("Restored {} to {:?}", sink.name, mode),
+                Err(e) => error!("Couldn't restore the mode of {}: {}", sink.name, e),
+            }
+        }
+    }
+}
 
+/// Reads the up channels and feeds the down channels until we're asked to close, a limit is hit
+/// or the connection is lost
+fn poll(
+    args: &Args,
+    capture: &mut Capture,
+    session: &mut Session,
+    cores: &[AttachedCore],
+    running: &AtomicBool,
+) -> Result<SessionEnd, Box<dyn std::error::Error>> {
     let mut buffer = [0u8; 1024];
+    let mut last_check = Instant::now();
+
+    let mut sequential_zeros = 0;
+    let mut sequential_errors = 0;
+    while running.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        let check_control_blocks =
+            args.reconnect && last_check.elapsed() >= CONTROL_BLOCK_CHECK_INTERVAL;
+        if check_control_blocks {
+            last_check = Instant::now();
+        }
+        let mut sending = false;
+        // Reads from each core in turn, the session only lets us hold one at a time
+        for attached in cores {
+            let core = &mut match session.core(attached.index) {
+                Ok(core) => core,
+                Err(e) if args.reconnect => return Ok(SessionEnd::Lost(e.into())),
+                Err(e) => return Err(e.into()),
+            };
+            for sink in capture
+                .sinks
+                .iter_mut()
+                .filter(|x| x.core == attached.index)
+            {
+                if !sink.working {
+                    trace!("Sink {} broken. Skipping", sink.name);
+                    continue;
+                }
+                if !sink.schedule.due(now) {
+                    continue;
+                }
+                let overflow = match sink.overflow.as_mut().map(|x| x.check(core)) {
+                    Some(Ok(overflow)) => overflow,
+                    Some(Err(e)) if args.reconnect => return Ok(SessionEnd::Lost(e.into())),
+                    Some(Err(e)) => {
+                        error!("Couldn't check {} for overflows: {}", sink.name, e);
+                        None
+                    }
+                    None => None,
+                };
+                if let Some(loss) = overflow {
+                    warn!("{}: {}", sink.name, loss);
+                    sink.stats.overflows += 1;
+                    sink.mark_loss(&loss);
+                }
+                let res = sink.channel.read(core, &mut buffer[..]);
+                let capacity = sink.channel.buffer_size();
+                match &res {
+                    Ok(bytes) => {
+                        sink.stats.read(*bytes);
+                        sink.schedule
+                            .record(*bytes, capacity, *bytes == buffer.len());
+                    }
+                    Err(_) => {
+                        sink.stats.error();
+                        sink.schedule.record(0, capacity, false);
+                    }
+                }
+                match res {
+                    Ok(bytes) if bytes > 0 => {
+                        trace!("Received data writing {} bytes from {}", bytes, sink.name);
+                        sequential_zeros = 0;
+                        sequential_errors = 0;
+                        capture.last_data = Instant::now();
+                        capture.received += bytes as u64;
+                        if let Some(outcome) = sink.write(&buffer[..bytes]) {
+                            info!("{} matched a {:?} pattern", sink.name, outcome);
+                            return Ok(SessionEnd::Finished(outcome));
+                        }
+                        if args
+                            .max_channel_bytes
+                            .is_some_and(|x| sink.stats.bytes >= x)
+                        {
+                            info!(
+                                "Stopping, {} reached the --max-channel-bytes limit with {} bytes",
+                                sink.name, sink.stats.bytes
+                            );
+                            return Ok(SessionEnd::Finished(Outcome::Stopped));
+                        }
+                    }
+                    Err(e) if args.reconnect => return Ok(SessionEnd::Lost(e.into())),
+                    Err(e) => {
+                        sequential_zeros = 0;
+                        sequential_errors += 1;
+                        error!("Channel error: {}", e);
+                        if sequential_errors >= MAX_SEQUENTIAL_ERRORS {
+                            return Err(format!(
+                                "giving up after {} channel errors in a row: {}",
+                                sequential_errors, e
+                            )
+                            .into());
+                        }
+                    }
+                    Ok(_) => {
+                        sequential_errors = 0;
+                        sequential_zeros += 1;
+                        if sequential_zeros % 100 == 1 {
+                            trace!("0 byte read #{}", sequential_zeros);
+                        }
+                    }
+                }
+            }
+            for source in capture
+                .sources
+                .iter_mut()
+                .filter(|x| x.core == attached.index && !x.finished)
+            {
+                match source.poll(core) {
+                    Ok(bytes) if bytes > 0 => {
+                        trace!("Sent {} bytes to {}", bytes, source.name);
+                        sending = true;
+                        sequential_errors = 0;
+                    }
+                    Ok(_) => sequential_errors = 0,
+                    Err(e) if args.reconnect => return Ok(SessionEnd::Lost(e.into())),
+                    Err(e) => {
+                        sequential_errors += 1;
+                        error!("Channel error: {}", e);
+                        if sequential_errors >= MAX_SEQUENTIAL_ERRORS {
+                            return Err(format!(
+                                "giving up after {} channel errors in a row: {}",
+                                sequential_errors, e
+                            )
+                            .into());
+                        }
+                    }
+                }
+            }
+            if check_control_blocks {
+                match control_block_valid(core, attached.rtt.ptr()) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return Ok(SessionEnd::Lost("RTT control block was invalidated".into()))
+                    }
+                    Err(e) => return Ok(SessionEnd::Lost(e.into())),
+                }
+            }
+        }
+        for sink in capture.sinks.iter_mut() {
+            sink.flush_if_due();
+        }
+        if let Some(outcome) = check_limits(args, capture) {
+            return Ok(SessionEnd::Finished(outcome));
+        }
+        if args.stats_interval > 0
+            && capture.last_stats.elapsed() >= Duration::from_secs(args.stats_interval)
+        {
+            capture.last_stats = Instant::now();
+            for sink in &capture.sinks {
+                sink.stats.log();
+            }
+        }
+        // Sleep until a channel is due, down channels being fed keep the loop going at the
+        // fastest rate
+        let max_wait = if sending {
+            Duration::from_millis(args.poll_min)
+        } else {
+            Duration::from_millis(args.poll_max)
+        };
+        let wake = capture
+            .sinks
+            .iter()
+            .filter(|x| x.working)
+            .map(|x| x.schedule.next())
+            .fold(Instant::now() + max_wait, Instant::min);
+        let wait = wake.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+    Ok(SessionEnd::Closed)
+}
+
+/// Checks the limits that apply to the whole capture, the timeouts fail the run while the
+/// others stop it cleanly
+fn check_limits(args: &Args, capture: &Capture) -> Option<Outcome> {
+    let expired = |limit: Option<u64>, since: Instant| {
+        limit.is_some_and(|x| since.elapsed() >= Duration::from_secs(x))
+    };
+    if expired(args.timeout, capture.start) {
+        warn!("Timed out after {}s", args.timeout.unwrap_or_default());
+        Some(Outcome::TimedOut)
+    } else if expired(args.idle_timeout, capture.last_data) {
+        warn!(
+            "No data received for {}s",
+            args.idle_timeout.unwrap_or_default()
+        );
+        Some(Outcome::TimedOut)
+    } else if expired(args.duration, capture.start) {
+        info!(
+            "Stopping, reached the --duration limit of {}s",
+            args.duration.unwrap_or_default()
+        );
+        Some(Outcome::Stopped)
+    } else if expired(args.max_idle, capture.last_data) {
+        info!(
+            "Stopping, reached the --max-idle limit of {}s without data",
+            args.max_idle.unwrap_or_default()
+        );
+        Some(Outcome::Stopped)
+    } else if args.max_bytes.is_some_and(|x| capture.received >= x) {
+        info!(
+            "Stopping, reached the --max-bytes limit with {} bytes",
+            capture.received
+        );
+        Some(Outcome::Stopped)
+    } else {
+        None
+    }
+}
+
+fn main() {
+    setup_tracing();
+
+    let code = match run() {
+        Ok(outcome) => {
+            info!("Finished: {:?}", outcome);
+            outcome.exit_code()
+        }
+        Err(e) => {
+            error!("{}", e);
+            ERROR_EXIT_CODE
+        }
+    };
+    std::process::exit(code);
+}
+
+fn run() -> Result<Outcome, Box<dyn std::error::Error>> {
+    let args = Args::from_args();
+    // Get channels dump to file
+    let config_file = args
+        .config
+        .clone()
+        .or_else(|| Some(PathBuf::from("Embed.toml")).filter(|x| x.exists()));
+
+    let config: Config = match config_file {
+        Some(config_file) => {
+            info!("Reading configuration file");
+            toml::from_str(&fs::read_to_string(config_file)?)?
+        }
+        None => {
+            info!("No configuration file, logging all channels");
+            Config::default()
+        }
+    };
+    check_config(&args, &config)?;
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -169,38 +1172,109 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let mut sequential_zeros = 0;
-    while running.load(Ordering::SeqCst) {
-        // To do move this into some sort of poll function
-        for sink in &mut sinks {
-            if !sink.working {
-                trace!("Sink {} broken. Skipping", sink.name);
-                continue;
-            }
-            let res = sink.channel.read(&mut core, &mut buffer[..]);
-            match res {
-                Ok(bytes) if bytes > 0 => {
-                    trace!("Received data writing {} bytes from {}", bytes, sink.name);
-                    sequential_zeros = 0;
-                    if let Err(e) = sink.file.write_all(&buffer[..bytes]) {
-                        error!("Failed to write data from {}: {}", sink.name, e);
-                        sink.working = false;
-                    }
-                }
-                Err(e) => {
-                    sequential_zeros = 0;
-                    error!("Channel error: {}", e);
-                }
-                Ok(_) => {
-                    sequential_zeros += 1;
-                    if sequential_zeros % 100 == 1 {
-                        trace!("0 byte read #{}", sequential_zeros);
+    let mut capture = Capture {
+        start: Instant::now(),
+        paths: PathContext {
+            chip: args.chip.clone(),
+            started: chrono::Local::now(),
+        },
+        sinks: vec![],
+        sources: vec![],
+        last_data: Instant::now(),
+        received: 0,
+        last_stats: Instant::now(),
+        flashed: false,
+        ready: vec![],
+    };
+    let mut backoff = Backoff::new(
+        Duration::from_millis(args.reconnect_delay),
+        Duration::from_millis(args.reconnect_max_delay),
+        args.reconnect_attempts,
+    );
+    let mut result = Ok(Outcome::Stopped);
+
+    'capture: while running.load(Ordering::SeqCst) {
+        let err = match run_session(&args, &config, &mut capture, &running) {
+            Ok(SessionEnd::Closed) => break,
+            Ok(SessionEnd::Finished(outcome)) => {
+                result = Ok(outcome);
+                break;
+            }
+            Ok(SessionEnd::Failed(e)) => {
+                result = Err(e);
+                break;
+            }
+            Ok(SessionEnd::Lost(e)) => {
+                backoff.reset();
+                warn!("Lost connection to the target: {}", e);
+                e
+            }
+            Err(e) if !args.reconnect => {
+                result = Err(e);
+                break;
+            }
+            Err(e) => {
+                error!("Failed to connect: {}", e);
+                e
+            }
+        };
+        match backoff.next_delay() {
+            Some(delay) => {
+                info!(
+                    "Reconnecting in {:?} (attempt {})",
+                    delay,
+                    backoff.attempts()
+                );
+                let start = Instant::now();
+                while running.load(Ordering::SeqCst) && start.elapsed() < delay {
+                    if let Some(outcome) = check_limits(&args, &capture) {
+                        result = Ok(outcome);
+                        break 'capture;
                     }
+                    thread::sleep(Duration::from_millis(10));
                 }
             }
+            None => {
+                error!("Giving up after {} attempts", backoff.attempts());
+                result = Err(err);
+                break;
+            }
         }
     }
+
+    // Without a verdict a stopped test run must not look like a pass
+    if matches!(result, Ok(Outcome::Stopped)) && capture.sinks.iter().any(|x| x.matcher.is_some()) {
+        warn!("Stopped before any pass or fail pattern matched");
+        result = Ok(Outcome::Incomplete);
+    }
+
+    for sink in &mut capture.sinks {
+        sink.finish();
+        sink.stats.log();
+    }
     info!("Closed");
 
-    Ok(())
+    if let Some(path) = args.summary.as_ref() {
+        let summary = Summary {
+            started: capture.paths.started.to_rfc3339(),
+            duration: capture.start.elapsed().as_secs_f64(),
+            outcome: match &result {
+                Ok(outcome) => outcome.name().to_string(),
+                Err(_) => "error".to_string(),
+            },
+            error: result.as_ref().err().map(ToString::to_string),
+            bytes: capture.received,
+            channels: capture.sinks.iter().map(|x| x.stats.clone()).collect(),
+        };
+        match fs::File::create(path) {
+            Ok(file) => {
+                if let Err(e) = serde_json::to_writer_pretty(file, &summary) {
+                    error!("Failed to write the summary to {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => error!("Couldn't create {}: {}", path.display(), e),
+        }
+    }
+
+    result
 }
@@ -0,0 +1,34 @@
+use probe_rs_rtt::ChannelMode;
+use serde::Deserialize;
+
+/// What the target does when an up channel's buffer is full
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Drop writes that don't fit
+    NoBlockSkip,
+    /// Write as much as fits and drop the rest
+    NoBlockTrim,
+    /// Wait for the host to read the channel, nothing is lost but the target can stall
+    BlockIfFull,
+}
+
+impl From<Mode> for ChannelMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::NoBlockSkip => Self::NoBlockSkip,
+            Mode::NoBlockTrim => Self::NoBlockTrim,
+            Mode::BlockIfFull => Self::BlockIfFull,
+        }
+    }
+}
+
+impl From<ChannelMode> for Mode {
+    fn from(mode: ChannelMode) -> Self {
+        match mode {
+            ChannelMode::NoBlockSkip => Self::NoBlockSkip,
+            ChannelMode::NoBlockTrim => Self::NoBlockTrim,
+            ChannelMode::BlockIfFull => Self::BlockIfFull,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What to do when a channel's output file already exists
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenPolicy {
+    /// Overwrite the existing file
+    #[default]
+    Truncate,
+    /// Add to the end of the existing file after a line marking the new session
+    Append,
+    /// Refuse to start
+    Fail,
+    /// Add a numeric suffix to the file name until it doesn't clash, e.g. `log-1.txt`
+    Unique,
+}
+
+/// Values substituted into output path templates
+#[derive(Debug, Clone)]
+pub struct PathContext {
+    pub chip: String,
+    pub started: DateTime<Local>,
+}
+
+impl PathContext {
+    /// Expands `{channel}`, `{index}`, `{chip}`, `{date}` and `{session}` in an output path
+    pub fn expand(&self, template: &Path, channel: &str, index: usize) -> PathBuf {
+        let expanded = template
+            .to_string_lossy()
+            .replace("{channel}", channel)
+            .replace("{index}", &index.to_string())
+            .replace("{chip}", &self.chip)
+            .replace("{date}", &self.started.format("%Y-%m-%d").to_string())
+            .replace(
+                "{session}",
+                &self.started.format("%Y%m%dT%H%M%S").to_string(),
+            );
+        PathBuf::from(expanded)
+    }
+}
+
+/// Adds `-n` to the end of the file stem, e.g. `log.txt` becomes `log-1.txt`
+pub fn stem_numbered_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|x| format!(".{}", x.to_string_lossy()))
+        .unwrap_or_default();
+    path.with_file_name(format!("{}-{}{}", stem, n, extension))
+}
+
+fn unique_path(path: &Path) -> PathBuf {
+    (1..)
+        .map(|n| stem_numbered_path(path, n))
+        .find(|x| !x.exists())
+        .expect("ran out of numbers")
+}
+
+/// The line written between sessions when appending to an existing file
+pub fn session_separator() -> String {
+    format!(
+        "\n=== rtt-file-logger: new session started at {} ===\n",
+        Local::now().to_rfc3339()
+    )
+}
+
+/// Opens an output file following `policy`, returning the path actually used. With
+/// `OpenPolicy::Append` the caller writes the `session_separator`, so it can go through any
+/// compression
+pub fn open(path: &Path, policy: OpenPolicy) -> io::Result<(PathBuf, fs::File)> {
+    match policy {
+        OpenPolicy::Truncate => Ok((path.to_path_buf(), fs::File::create(path)?)),
+        OpenPolicy::Append => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok((path.to_path_buf(), file))
+        }
+        OpenPolicy::Fail => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| {
+                    if e.kind() == io::ErrorKind::AlreadyExists {
+                        io::Error::new(e.kind(), format!("{} already exists", path.display()))
+                    } else {
+                        e
+                    }
+                })?;
+            Ok((path.to_path_buf(), file))
+        }
+        OpenPolicy::Unique => {
+            let path = if path.exists() {
+                unique_path(path)
+            } else {
+                path.to_path_buf()
+            };
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            Ok((path, file))
+        }
+    }
+}
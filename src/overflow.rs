@@ -0,0 +1,59 @@
+use probe_rs::{Core, MemoryInterface};
+
+/// Offset of the first channel descriptor in the control block
+const CHANNEL_ARRAYS_OFFSET: u32 = 24;
+/// Size of each channel descriptor
+const CHANNEL_SIZE: u32 = 24;
+/// Offset of the write offset in a channel descriptor, followed by the read offset and flags
+const WRITE_OFFSET: u32 = 12;
+/// Mode bits in the channel flags
+const MODE_MASK: u32 = 0x3;
+/// Mode in which the target waits for space instead of dropping data
+const MODE_BLOCK_IF_FULL: u32 = 2;
+/// Fill level, in quarters of the buffer, above which a non-blocking target is likely dropping
+/// writes. Writes that don't fit are discarded whole, so the buffer rarely ends up exactly full
+const HIGH_WATER_QUARTERS: u32 = 3;
+
+/// Watches an up channel's ring buffer for signs that the target dropped data. Before each read
+/// the fill level is checked, a buffer past the high-water mark in a non-blocking mode means the
+/// target is probably discarding writes that don't fit.
+#[derive(Debug)]
+pub struct OverflowDetector {
+    /// Address of the channel descriptor
+    address: u32,
+    size: u32,
+    /// Set while the buffer is above the high-water mark so each episode is reported once
+    full: bool,
+}
+
+impl OverflowDetector {
+    pub fn new(control_block: u32, number: usize, size: usize) -> Self {
+        Self {
+            address: control_block + CHANNEL_ARRAYS_OFFSET + number as u32 * CHANNEL_SIZE,
+            size: size as u32,
+            full: false,
+        }
+    }
+
+    /// Checks the ring buffer before a read, returning a description of any suspected data loss
+    pub fn check(&mut self, core: &mut Core) -> Result<Option<String>, probe_rs::Error> {
+        let mut words = [0u32; 3];
+        core.read_32(self.address + WRITE_OFFSET, &mut words)?;
+        let [write, read, flags] = words;
+        if self.size == 0 || write >= self.size || read >= self.size {
+            // Reading the channel will report the corrupt control block
+            return Ok(None);
+        }
+        let fill = (write + self.size - read) % self.size;
+        let full =
+            fill * 4 >= self.size * HIGH_WATER_QUARTERS && flags & MODE_MASK != MODE_BLOCK_IF_FULL;
+        let first_full = full && !self.full;
+        self.full = full;
+        Ok(first_full.then(|| {
+            format!(
+                "buffer nearly full ({} of {} bytes), the target may be dropping data",
+                fill, self.size
+            )
+        }))
+    }
+}
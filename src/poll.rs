@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+/// How full we aim for a channel's buffer to be when it's read, leaving headroom for bursts
+const TARGET_FILL: f64 = 0.25;
+/// Smallest interval to back off from when the minimum is zero
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Works out when to next read a channel. From the amount of data each read returns it
+/// estimates how quickly the target is filling the buffer and picks an interval that reads it
+/// around a quarter full. Empty reads double the interval so idle channels back off, and the
+/// interval is kept between the configured limits.
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    min: Duration,
+    max: Duration,
+    interval: Duration,
+    last: Instant,
+    next: Instant,
+}
+
+impl PollSchedule {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            min,
+            max: max.max(min),
+            interval: min,
+            last: now,
+            next: now,
+        }
+    }
+
+    /// Whether the channel should be read now
+    pub fn due(&self, now: Instant) -> bool {
+        now >= self.next
+    }
+
+    /// When the channel next needs reading
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+
+    /// Updates the schedule after a read returned `bytes` from a channel with a buffer of
+    /// `capacity` bytes. `more` means the read stopped early and there's data left to read
+    pub fn record(&mut self, bytes: usize, capacity: usize, more: bool) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        self.interval = if more {
+            self.min
+        } else if bytes == 0 {
+            (self.interval * 2).max(MIN_BACKOFF).min(self.max)
+        } else {
+            let fill = bytes as f64 / capacity.max(1) as f64;
+            let ideal = elapsed.mul_f64(TARGET_FILL / fill);
+            // Don't back off too fast on a single quiet read
+            ideal
+                .min((self.interval * 2).max(MIN_BACKOFF))
+                .clamp(self.min, self.max)
+        };
+        self.next = if more { now } else { now + self.interval };
+    }
+}
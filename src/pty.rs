@@ -0,0 +1,127 @@
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Exposes an up channel and a down channel as a pseudo-terminal
+#[derive(Debug, Clone, Deserialize)]
+pub struct PtyConfig {
+    /// Down channel that input typed into the terminal is sent to
+    pub down: usize,
+    /// Symlink to create pointing at the terminal device, so it has a predictable name
+    pub link: Option<PathBuf>,
+}
+
+/// A pseudo-terminal in raw mode. Programs like minicom or screen open the slave device, the
+/// logger writes target output to the master side and reads their input from it.
+#[derive(Debug)]
+pub struct Pty {
+    master: fs::File,
+    /// Kept open so reading the master doesn't fail while no program has the terminal open
+    _slave: fs::File,
+    path: PathBuf,
+    link: Option<PathBuf>,
+}
+
+impl Pty {
+    #[cfg(unix)]
+    pub fn open(link: Option<&Path>) -> io::Result<Self> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use nix::pty::openpty;
+        use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+        use nix::unistd::ttyname;
+        use std::os::fd::AsRawFd;
+
+        let pty = openpty(None, None)?;
+        // Without raw mode the line discipline would echo target output back to the target
+        let mut termios = tcgetattr(&pty.slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)?;
+        // Writes to the master block when nobody is reading the terminal, we'd rather drop data
+        // than stall the RTT poll loop
+        let flags = OFlag::from_bits_truncate(fcntl(pty.master.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            pty.master.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )?;
+
+        let path = ttyname(&pty.slave)?;
+        if let Some(link) = link {
+            if fs::symlink_metadata(link).is_ok() {
+                fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&path, link)?;
+        }
+        Ok(Self {
+            master: pty.master.into(),
+            _slave: pty.slave.into(),
+            path,
+            link: link.map(Path::to_path_buf),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(_link: Option<&Path>) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pseudo-terminals are only supported on unix",
+        ))
+    }
+
+    /// Path of the terminal device for other programs to open
+    pub fn path(&self) -> &Path {
+        self.link.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Reads input typed into the terminal. Reads return `WouldBlock` when there's no input
+    pub fn reader(&self) -> io::Result<fs::File> {
+        self.master.try_clone()
+    }
+
+    /// Writes target output to the terminal
+    pub fn writer(&self) -> io::Result<PtyWriter> {
+        Ok(PtyWriter {
+            master: self.master.try_clone()?,
+            dropping: false,
+        })
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = self.link.as_ref() {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
+
+/// Writes to the master side of a `Pty`, dropping data while the terminal's buffer is full
+#[derive(Debug)]
+pub struct PtyWriter {
+    master: fs::File,
+    dropping: bool,
+}
+
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            Ok(n) => {
+                self.dropping = false;
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if !self.dropping {
+                    warn!("Terminal isn't being read, dropping data");
+                    self.dropping = true;
+                }
+                Ok(buf.len())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
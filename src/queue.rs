@@ -0,0 +1,64 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tracing::warn;
+
+/// Number of writes queued before data starts getting dropped
+const QUEUE_DEPTH: usize = 256;
+
+/// Feeds a blocking writer from its own thread through a bounded queue, so a slow reader on the
+/// other end loses data rather than holding up the RTT poll loop.
+#[derive(Debug)]
+pub struct QueuedWriter {
+    tx: SyncSender<Arc<[u8]>>,
+    thread: JoinHandle<()>,
+    /// Set while the queue is full so we only warn once per episode
+    dropping: bool,
+}
+
+impl QueuedWriter {
+    /// Starts the writer thread, `closed` is called on it if a write fails
+    pub fn spawn<W, F>(mut writer: W, closed: F) -> Self
+    where
+        W: Write + Send + 'static,
+        F: FnOnce(io::Error) + Send + 'static,
+    {
+        let (tx, rx) = sync_channel::<Arc<[u8]>>(QUEUE_DEPTH);
+        let thread = thread::spawn(move || {
+            for data in rx {
+                if let Err(e) = writer.write_all(&data) {
+                    closed(e);
+                    return;
+                }
+            }
+        });
+        Self {
+            tx,
+            thread,
+            dropping: false,
+        }
+    }
+
+    /// Queues data for writing, dropping it with a warning naming `reader` if the queue is full.
+    /// Returns false once the writer thread has stopped
+    pub fn send(&mut self, data: Arc<[u8]>, reader: impl fmt::Display) -> bool {
+        match self.tx.try_send(data) {
+            Ok(()) => self.dropping = false,
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping {
+                    warn!("{} can't keep up, dropping data", reader);
+                    self.dropping = true;
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => return false,
+        }
+        true
+    }
+
+    /// Stops queueing, the thread exits once it has written what's left
+    pub fn close(self) -> JoinHandle<()> {
+        self.thread
+    }
+}
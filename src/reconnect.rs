@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+/// Exponential backoff between attempts to reconnect to the target
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    limit: Option<usize>,
+    delay: Duration,
+    attempts: usize,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, limit: Option<usize>) -> Self {
+        Self {
+            initial,
+            max,
+            limit,
+            delay: initial,
+            attempts: 0,
+        }
+    }
+
+    /// Number of failed attempts since the last successful connection
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// Returns how long to wait before the next attempt, or `None` if we've hit the attempt limit
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if matches!(self.limit, Some(limit) if self.attempts >= limit) {
+            return None;
+        }
+        let delay = self.delay;
+        self.attempts += 1;
+        self.delay = (self.delay * 2).min(self.max);
+        Some(delay)
+    }
+
+    /// Called once a connection has been established
+    pub fn reset(&mut self) {
+        self.delay = self.initial;
+        self.attempts = 0;
+    }
+}
@@ -0,0 +1,378 @@
+use crate::timestamp::{wall_time, Timestamp};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+use tracing::warn;
+
+/// Describes the messages in a postcard encoded channel, postcard isn't self-describing so the
+/// types have to be given to decode it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schema {
+    /// Variants of the message enum, in declaration order
+    variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Variant {
+    name: String,
+    /// Fields of a struct-like variant, empty for a unit variant
+    #[serde(default)]
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Field {
+    name: String,
+    #[serde(rename = "type")]
+    ty: Type,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Type {
+    Primitive(Primitive),
+    Option { option: Box<Type> },
+    Seq { seq: Box<Type> },
+    Array { array: Box<Type>, len: usize },
+    Struct { fields: Vec<Field> },
+    Enum { variants: Vec<Variant> },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Primitive {
+    Unit,
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    String,
+    Bytes,
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("couldn't parse {}: {}", path.display(), e))
+    }
+}
+
+/// Reads postcard values from a frame
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.data.len() {
+            return Err("frame ended early".to_string());
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".to_string())
+    }
+
+    fn signed(&mut self) -> Result<i64, String> {
+        let zigzag = self.varint()?;
+        Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        let len = self.varint()? as usize;
+        if len > self.data.len() {
+            return Err(format!("length {} is longer than the frame", len));
+        }
+        Ok(len)
+    }
+
+    fn primitive(&mut self, ty: Primitive) -> Result<Value, String> {
+        Ok(match ty {
+            Primitive::Unit => Value::Null,
+            Primitive::Bool => match self.take(1)?[0] {
+                0 => false.into(),
+                1 => true.into(),
+                x => return Err(format!("invalid bool {}", x)),
+            },
+            Primitive::U8 => self.take(1)?[0].into(),
+            Primitive::I8 => (self.take(1)?[0] as i8).into(),
+            Primitive::U16 | Primitive::U32 | Primitive::U64 => self.varint()?.into(),
+            Primitive::I16 | Primitive::I32 | Primitive::I64 => self.signed()?.into(),
+            Primitive::F32 => f32::from_le_bytes(self.take(4)?.try_into().unwrap()).into(),
+            Primitive::F64 => f64::from_le_bytes(self.take(8)?.try_into().unwrap()).into(),
+            Primitive::String => {
+                let len = self.len()?;
+                String::from_utf8(self.take(len)?.to_vec())
+                    .map_err(|_| "string isn't valid UTF-8".to_string())?
+                    .into()
+            }
+            Primitive::Bytes => {
+                let len = self.len()?;
+                self.take(len)?.to_vec().into()
+            }
+        })
+    }
+
+    fn fields(&mut self, fields: &[Field]) -> Result<Value, String> {
+        let mut object = Map::new();
+        for field in fields {
+            object.insert(field.name.clone(), self.value(&field.ty)?);
+        }
+        Ok(Value::Object(object))
+    }
+
+    /// Reads an enum, returning the variant's name and its fields
+    fn variant(&mut self, variants: &[Variant]) -> Result<(String, Value), String> {
+        let index = self.varint()?;
+        let variant = variants
+            .get(index as usize)
+            .ok_or_else(|| format!("unknown variant {}", index))?;
+        Ok((variant.name.clone(), self.fields(&variant.fields)?))
+    }
+
+    fn value(&mut self, ty: &Type) -> Result<Value, String> {
+        match ty {
+            Type::Primitive(x) => self.primitive(*x),
+            Type::Option { option } => match self.take(1)?[0] {
+                0 => Ok(Value::Null),
+                1 => self.value(option),
+                x => Err(format!("invalid option tag {}", x)),
+            },
+            Type::Seq { seq } => {
+                let len = self.len()?;
+                (0..len).map(|_| self.value(seq)).collect()
+            }
+            Type::Array { array, len } => (0..*len).map(|_| self.value(array)).collect(),
+            Type::Struct { fields } => self.fields(fields),
+            Type::Enum { variants } => {
+                let (name, fields) = self.variant(variants)?;
+                Ok(json!({ name: fields }))
+            }
+        }
+    }
+}
+
+/// Converts a CBOR value into the closest JSON equivalent. Map keys that aren't strings are
+/// written as their JSON text
+fn cbor_to_json(value: ciborium::Value) -> Value {
+    use ciborium::Value as Cbor;
+    match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(x) => x.into(),
+        Cbor::Integer(x) => {
+            let x = i128::from(x);
+            i64::try_from(x)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(x).map(Value::from))
+                .unwrap_or_else(|_| x.to_string().into())
+        }
+        Cbor::Float(x) => x.into(),
+        Cbor::Text(x) => x.into(),
+        Cbor::Bytes(x) => x.into(),
+        Cbor::Tag(_, x) => cbor_to_json(*x),
+        Cbor::Array(x) => x.into_iter().map(cbor_to_json).collect(),
+        Cbor::Map(x) => x
+            .into_iter()
+            .map(|(k, v)| {
+                let key = match cbor_to_json(k) {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                (key, cbor_to_json(v))
+            })
+            .collect::<Map<_, _>>()
+            .into(),
+        _ => Value::Null,
+    }
+}
+
+#[derive(Debug)]
+enum Encoding {
+    Cbor,
+    Postcard(Schema),
+}
+
+/// Decodes each frame of a channel as a CBOR or postcard record and writes it as a line of JSON
+/// with the channel name and the host time it was received
+#[derive(Debug)]
+pub struct RecordDecoder {
+    encoding: Encoding,
+    channel: String,
+    timestamp: Timestamp,
+    start: Instant,
+}
+
+impl RecordDecoder {
+    pub fn cbor(channel: &str, timestamp: Timestamp, start: Instant) -> Self {
+        Self {
+            encoding: Encoding::Cbor,
+            channel: channel.to_string(),
+            timestamp,
+            start,
+        }
+    }
+
+    pub fn postcard(schema: Schema, channel: &str, timestamp: Timestamp, start: Instant) -> Self {
+        Self {
+            encoding: Encoding::Postcard(schema),
+            channel: channel.to_string(),
+            timestamp,
+            start,
+        }
+    }
+
+    fn decode(&self, frame: &[u8], record: &mut Map<String, Value>) -> Result<(), String> {
+        match &self.encoding {
+            Encoding::Cbor => {
+                let value: ciborium::Value =
+                    ciborium::from_reader(frame).map_err(|e| e.to_string())?;
+                record.insert("data".into(), cbor_to_json(value));
+            }
+            Encoding::Postcard(schema) => {
+                let mut reader = Reader { data: frame };
+                let (variant, data) = reader.variant(&schema.variants)?;
+                if !reader.data.is_empty() {
+                    return Err(format!("{} bytes left over", reader.data.len()));
+                }
+                record.insert("variant".into(), variant.into());
+                record.insert("data".into(), data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a record with the channel name and the time fields
+    fn header(&self) -> Map<String, Value> {
+        let mut record = Map::new();
+        record.insert("channel".into(), self.channel.clone().into());
+        if matches!(self.timestamp, Timestamp::Wall | Timestamp::Both) {
+            record.insert("time".into(), wall_time().into());
+        }
+        if matches!(self.timestamp, Timestamp::Monotonic | Timestamp::Both) {
+            record.insert("elapsed".into(), self.start.elapsed().as_secs_f64().into());
+        }
+        record
+    }
+
+    /// Writes a record marking a gap in the data, so the output stays valid JSON Lines
+    pub fn write_gap(&self, message: &str, out: &mut impl Write) -> io::Result<()> {
+        let mut record = self.header();
+        record.insert("gap".into(), message.into());
+        writeln!(out, "{}", Value::Object(record))
+    }
+
+    pub fn write(&mut self, frame: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let mut record = self.header();
+        if let Err(e) = self.decode(frame, &mut record) {
+            warn!("Couldn't decode a record on {}: {}", self.channel, e);
+            record.insert("error".into(), e.into());
+        }
+        writeln!(out, "{}", Value::Object(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(data: &[u8]) -> Reader<'_> {
+        Reader { data }
+    }
+
+    #[test]
+    fn varint() {
+        assert_eq!(reader(&[0x00]).varint(), Ok(0));
+        assert_eq!(reader(&[0x7f]).varint(), Ok(127));
+        assert_eq!(reader(&[0x80, 0x01]).varint(), Ok(128));
+        assert_eq!(reader(&[0xac, 0x02]).varint(), Ok(300));
+        assert!(reader(&[0x80]).varint().is_err());
+        assert!(reader(&[0xff; 11]).varint().is_err());
+    }
+
+    #[test]
+    fn zigzag() {
+        assert_eq!(reader(&[0x00]).signed(), Ok(0));
+        assert_eq!(reader(&[0x01]).signed(), Ok(-1));
+        assert_eq!(reader(&[0x02]).signed(), Ok(1));
+        assert_eq!(reader(&[0x7f]).signed(), Ok(-64));
+        assert_eq!(reader(&[0xd7, 0x04]).signed(), Ok(-300));
+    }
+
+    #[test]
+    fn oversized_length() {
+        assert!(reader(&[0x05, b'a', b'b'])
+            .primitive(Primitive::String)
+            .is_err());
+        assert!(reader(&[0xff, 0xff, 0x03])
+            .primitive(Primitive::Bytes)
+            .is_err());
+        let mut seq = reader(&[0x02, 0x01]);
+        let ty = Type::Seq {
+            seq: Box::new(Type::Primitive(Primitive::U8)),
+        };
+        assert!(seq.value(&ty).is_err());
+    }
+
+    #[test]
+    fn postcard_record() {
+        let schema: Schema = toml::from_str(
+            r#"
+            [[variants]]
+            name = "Boot"
+
+            [[variants]]
+            name = "Reading"
+            fields = [
+                { name = "sensor", type = "u8" },
+                { name = "value", type = "i32" },
+                { name = "label", type = { option = "string" } },
+            ]
+            "#,
+        )
+        .unwrap();
+        let decoder = RecordDecoder::postcard(schema, "test", Timestamp::Wall, Instant::now());
+        let mut record = Map::new();
+        decoder
+            .decode(&[0x01, 0x07, 0x01, 0x01, 0x02, b'h', b'i'], &mut record)
+            .unwrap();
+        assert_eq!(record["variant"], "Reading");
+        assert_eq!(
+            record["data"],
+            json!({ "sensor": 7, "value": -1, "label": "hi" })
+        );
+        assert!(decoder.decode(&[0x00, 0x00], &mut Map::new()).is_err());
+        assert!(decoder.decode(&[0x02], &mut Map::new()).is_err());
+    }
+
+    #[test]
+    fn cbor_record() {
+        let decoder = RecordDecoder::cbor("test", Timestamp::Wall, Instant::now());
+        let mut record = Map::new();
+        // {"a": -2, 1: [true]}
+        let frame = [0xa2, 0x61, b'a', 0x21, 0x01, 0x81, 0xf5];
+        decoder.decode(&frame, &mut record).unwrap();
+        assert_eq!(record["data"], json!({ "a": -2, "1": [true] }));
+    }
+}
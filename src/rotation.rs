@@ -0,0 +1,278 @@
+use crate::compression::{Compression, Encoder};
+use crate::output::{self, OpenPolicy};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How rotated files are named relative to the configured output path
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Naming {
+    /// `log.txt.1` is the most recent rotated file, `log.txt.2` the one before it and so on
+    #[default]
+    Numbered,
+    /// Rotated files get the local time they were rotated at appended, e.g.
+    /// `log.txt.20211130T153000`
+    Timestamped,
+}
+
+/// Rotation settings for a channel's output file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Rotation {
+    /// Rotate once the file would grow beyond this many bytes
+    max_size: Option<u64>,
+    /// Rotate once the file has been open for this many seconds
+    max_age: Option<u64>,
+    /// Number of rotated files to keep, all of them are kept if this isn't set
+    keep: Option<usize>,
+    #[serde(default)]
+    naming: Naming,
+}
+
+/// An output file which is rotated according to the channel's `Rotation` settings. Rotation is
+/// only checked at the start of a `write` call so every buffer handed to the writer ends up
+/// entirely in one file.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: Encoder,
+    compression: Compression,
+    rotation: Option<Rotation>,
+    written: u64,
+    opened: Instant,
+    rotated: VecDeque<PathBuf>,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: &Path,
+        policy: OpenPolicy,
+        compression: Compression,
+        rotation: Option<Rotation>,
+    ) -> io::Result<Self> {
+        let (path, file) = output::open(path, policy)?;
+        let existing = file.metadata()?.len();
+        let mut file = Encoder::new(file, compression)?;
+        if policy == OpenPolicy::Append && existing > 0 {
+            // A compressed file gets a new stream, so the separator has to be compressed too
+            file.write_all(output::session_separator().as_bytes())?;
+        }
+        // Sizes are counted before compression, which isn't known for what's already in a
+        // compressed file, so only this session's data counts towards rotating it
+        let written = if compression == Compression::None {
+            existing
+        } else {
+            0
+        };
+        Ok(Self {
+            path,
+            written,
+            file,
+            compression,
+            rotation,
+            opened: Instant::now(),
+            rotated: VecDeque::new(),
+        })
+    }
+
+    /// The path being written to, which may differ from the configured one depending on the
+    /// `OpenPolicy`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        let rotation = match self.rotation.as_ref() {
+            Some(r) if self.written > 0 => r,
+            _ => return false,
+        };
+        let too_big = rotation
+            .max_size
+            .map(|max| self.written + incoming as u64 > max)
+            .unwrap_or(false);
+        let too_old = rotation
+            .max_age
+            .map(|age| self.opened.elapsed() >= Duration::from_secs(age))
+            .unwrap_or(false);
+        too_big || too_old
+    }
+
+    /// Flushes compressed data that has been waiting since the last flush interval
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        self.file.flush_if_due()
+    }
+
+    /// Ends the current file, finishing any compressed stream
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.finish()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.finish()?;
+        let rotation = self.rotation.clone().unwrap_or_default();
+        if rotation.keep == Some(0) {
+            info!(
+                "Discarding {} as no rotated files are kept",
+                self.path.display()
+            );
+            return self.reopen();
+        }
+        let rotated = match rotation.naming {
+            Naming::Numbered => {
+                self.shift_numbered(rotation.keep)?;
+                numbered_path(&self.path, 1)
+            }
+            Naming::Timestamped => {
+                let stamp = chrono::Local::now().format("%Y%m%dT%H%M%S").to_string();
+                let mut rotated = suffixed_path(&self.path, &stamp);
+                let mut n = 1;
+                while rotated.exists() {
+                    rotated = suffixed_path(&self.path, &format!("{}-{}", stamp, n));
+                    n += 1;
+                }
+                rotated
+            }
+        };
+        fs::rename(&self.path, &rotated)?;
+        info!("Rotated {} to {}", self.path.display(), rotated.display());
+
+        if rotation.naming == Naming::Timestamped {
+            self.rotated.push_back(rotated);
+            if let Some(keep) = rotation.keep {
+                while self.rotated.len() > keep {
+                    if let Some(old) = self.rotated.pop_front() {
+                        if let Err(e) = fs::remove_file(&old) {
+                            warn!("Failed to remove old file {}: {}", old.display(), e);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = Encoder::new(fs::File::create(&self.path)?, self.compression)?;
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Moves `path.n` to `path.n+1` so `path.1` is free for the file being rotated. Anything that
+    /// would end up beyond `keep` is removed.
+    fn shift_numbered(&self, keep: Option<usize>) -> io::Result<()> {
+        let mut last = 1;
+        while numbered_path(&self.path, last).exists() {
+            last += 1;
+        }
+        // `last` is now the first free index
+        for n in (1..last).rev() {
+            let from = numbered_path(&self.path, n);
+            match keep {
+                Some(keep) if n >= keep => fs::remove_file(&from)?,
+                _ => fs::rename(&from, numbered_path(&self.path, n + 1))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    suffixed_path(path, &n.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotating(path: &Path, max_size: u64, keep: Option<usize>) -> RotatingFile {
+        let rotation = Rotation {
+            max_size: Some(max_size),
+            keep,
+            ..Default::default()
+        };
+        RotatingFile::open(
+            path,
+            OpenPolicy::Truncate,
+            Compression::None,
+            Some(rotation),
+        )
+        .unwrap()
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn numbered_shifting_with_keep() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.txt");
+        let mut file = rotating(&path, 10, Some(2));
+        for chunk in ["aaaaaaaa", "bbbbbbbb", "cccccccc", "dddddddd"] {
+            file.write_all(chunk.as_bytes()).unwrap();
+        }
+        file.finish().unwrap();
+        assert_eq!(read(path.clone()), "dddddddd");
+        assert_eq!(read(numbered_path(&path, 1)), "cccccccc");
+        assert_eq!(read(numbered_path(&path, 2)), "bbbbbbbb");
+        assert!(!numbered_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn keep_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.txt");
+        let mut file = rotating(&path, 10, Some(0));
+        for chunk in ["aaaaaaaa", "bbbbbbbb", "cccccccc"] {
+            file.write_all(chunk.as_bytes()).unwrap();
+        }
+        file.finish().unwrap();
+        assert_eq!(read(path.clone()), "cccccccc");
+        assert!(!numbered_path(&path, 1).exists());
+    }
+
+    #[test]
+    fn write_larger_than_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.txt");
+        let mut file = rotating(&path, 4, None);
+        // A buffer is never split, even if it's bigger than a whole file
+        file.write_all(b"0123456789").unwrap();
+        file.write_all(b"ab").unwrap();
+        file.write_all(b"cd").unwrap();
+        file.write_all(b"efghijk").unwrap();
+        file.finish().unwrap();
+        assert_eq!(read(numbered_path(&path, 2)), "0123456789");
+        assert_eq!(read(numbered_path(&path, 1)), "abcd");
+        assert_eq!(read(path.clone()), "efghijk");
+        assert!(!numbered_path(&path, 3).exists());
+    }
+}
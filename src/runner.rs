@@ -0,0 +1,171 @@
+use regex::Regex;
+use serde::Deserialize;
+
+/// Exit code used when the logger itself fails, e.g. the probe can't be opened or is lost
+pub const ERROR_EXIT_CODE: i32 = 3;
+/// Partial lines longer than this are dropped, so a channel that never sends a newline can't
+/// grow the buffer without limit
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Text to look for in a channel, either a literal string or `{ regex = "..." }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Pattern {
+    Literal(String),
+    Regex { regex: String },
+}
+
+impl Pattern {
+    fn compile(&self) -> Result<Regex, regex::Error> {
+        match self {
+            Self::Literal(s) => Regex::new(&regex::escape(s)),
+            Self::Regex { regex } => Regex::new(regex),
+        }
+    }
+}
+
+/// Why the capture ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Stopped by the user or a limit, rather than by the test result
+    Stopped,
+    /// A pass pattern matched
+    Passed,
+    /// A fail pattern matched
+    Failed,
+    /// No pattern matched before the timeout or idle timeout
+    TimedOut,
+    /// Stopped by the user or a limit before any of the pass or fail patterns matched
+    Incomplete,
+}
+
+impl Outcome {
+    /// Name used for the outcome in the capture summary
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stopped => "stopped",
+            Self::Passed => "passed",
+            Self::Failed => "failed",
+            Self::TimedOut => "timed_out",
+            Self::Incomplete => "incomplete",
+        }
+    }
+
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Stopped | Self::Passed => 0,
+            Self::Failed => 1,
+            Self::TimedOut => 2,
+            Self::Incomplete => 4,
+        }
+    }
+}
+
+/// Looks for pass and fail patterns in each line of a channel. Fail patterns are checked first so
+/// a line matching both counts as a failure.
+#[derive(Debug)]
+pub struct LineMatcher {
+    pass: Vec<Regex>,
+    fail: Vec<Regex>,
+    /// The line received so far, checked as it arrives so markers without a newline still match
+    line: Vec<u8>,
+}
+
+impl LineMatcher {
+    /// Returns `None` if there aren't any patterns to look for
+    pub fn new(pass: &[Pattern], fail: &[Pattern]) -> Result<Option<Self>, regex::Error> {
+        if pass.is_empty() && fail.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            pass: pass
+                .iter()
+                .map(Pattern::compile)
+                .collect::<Result<_, _>>()?,
+            fail: fail
+                .iter()
+                .map(Pattern::compile)
+                .collect::<Result<_, _>>()?,
+            line: vec![],
+        }))
+    }
+
+    fn check_line(&self) -> Option<Outcome> {
+        let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = String::from_utf8_lossy(line);
+        if self.fail.iter().any(|x| x.is_match(&line)) {
+            Some(Outcome::Failed)
+        } else if self.pass.iter().any(|x| x.is_match(&line)) {
+            Some(Outcome::Passed)
+        } else {
+            None
+        }
+    }
+
+    /// Feeds in data read from the channel, returning the result once a pattern matches
+    pub fn check(&mut self, data: &[u8]) -> Option<Outcome> {
+        for chunk in data.split_inclusive(|x| *x == b'\n') {
+            self.line.extend_from_slice(chunk);
+            let outcome = self.check_line();
+            if self.line.ends_with(b"\n") || self.line.len() > MAX_LINE_LENGTH {
+                self.line.clear();
+            }
+            if outcome.is_some() {
+                return outcome;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(pass: &str) -> LineMatcher {
+        let pass = [Pattern::Regex {
+            regex: pass.to_string(),
+        }];
+        let fail = [Pattern::Literal("FAILED".to_string())];
+        LineMatcher::new(&pass, &fail).unwrap().unwrap()
+    }
+
+    #[test]
+    fn anchored_patterns_ignore_line_endings() {
+        assert_eq!(
+            matcher("passed$").check(b"all passed\n"),
+            Some(Outcome::Passed)
+        );
+        assert_eq!(
+            matcher("passed$").check(b"all passed\r\n"),
+            Some(Outcome::Passed)
+        );
+        assert_eq!(matcher("^passed$").check(b"not passed\n"), None);
+    }
+
+    #[test]
+    fn line_split_across_reads() {
+        let mut matcher = matcher("^all passed$");
+        assert_eq!(matcher.check(b"boot\nall "), None);
+        assert_eq!(matcher.check(b"passed\n"), Some(Outcome::Passed));
+    }
+
+    #[test]
+    fn fail_wins() {
+        assert_eq!(
+            matcher("passed").check(b"passed then FAILED\n"),
+            Some(Outcome::Failed)
+        );
+    }
+
+    #[test]
+    fn long_lines_dropped() {
+        let mut matcher = matcher("^x+y$");
+        assert_eq!(matcher.check(&[b'x'; MAX_LINE_LENGTH]), None);
+        assert_eq!(matcher.check(b"xx"), None);
+        assert!(matcher.line.len() <= MAX_LINE_LENGTH);
+        // The matcher carries on with whatever comes next
+        assert_eq!(matcher.check(b"xy\n"), Some(Outcome::Passed));
+    }
+}
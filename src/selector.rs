@@ -0,0 +1,151 @@
+use probe_rs::{DebugProbeInfo, DebugProbeSelector};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Picks a probe out of `Probe::list_all()`. Either `VID:PID` or `VID:PID:SERIAL` in hex like
+/// probe-rs uses, or a bare serial number. A bare number which doesn't match any serial number is
+/// treated as an index into the probe list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ProbeSelector {
+    Usb(DebugProbeSelector),
+    Serial(String),
+}
+
+impl FromStr for ProbeSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            s.parse().map(Self::Usb).map_err(|e| e.to_string())
+        } else {
+            Ok(Self::Serial(s.to_string()))
+        }
+    }
+}
+
+impl TryFrom<String> for ProbeSelector {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Default for ProbeSelector {
+    fn default() -> Self {
+        Self::Serial("0".to_string())
+    }
+}
+
+impl fmt::Display for ProbeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Usb(sel) => {
+                write!(f, "{:04x}:{:04x}", sel.vendor_id, sel.product_id)?;
+                if let Some(serial) = sel.serial_number.as_ref() {
+                    write!(f, ":{}", serial)?;
+                }
+                Ok(())
+            }
+            Self::Serial(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl ProbeSelector {
+    fn matches(&self, probe: &DebugProbeInfo) -> bool {
+        match self {
+            Self::Usb(sel) => {
+                sel.vendor_id == probe.vendor_id
+                    && sel.product_id == probe.product_id
+                    && (sel.serial_number.is_none() || sel.serial_number == probe.serial_number)
+            }
+            Self::Serial(s) => probe.serial_number.as_deref() == Some(s.as_str()),
+        }
+    }
+
+    /// Finds the one probe this selector refers to, erroring if there are none or several
+    pub fn select<'a>(&self, probes: &'a [DebugProbeInfo]) -> Result<&'a DebugProbeInfo, String> {
+        let matching = probes
+            .iter()
+            .filter(|x| self.matches(x))
+            .collect::<Vec<_>>();
+        match (matching.as_slice(), self) {
+            ([probe], _) => Ok(probe),
+            ([], Self::Serial(s)) => match s.parse::<usize>() {
+                Ok(index) => probes.get(index).ok_or_else(|| {
+                    format!(
+                        "No probe with serial number or index {} ({} probes connected)",
+                        s,
+                        probes.len()
+                    )
+                }),
+                Err(_) => Err(format!("No probe with serial number {}", s)),
+            },
+            ([], Self::Usb(_)) => Err(format!("No probe matches '{}'", self)),
+            (many, _) => Err(format!(
+                "'{}' matches {} probes, use VID:PID:SERIAL to pick one of: {:?}",
+                self,
+                many.len(),
+                many
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use probe_rs::DebugProbeType;
+
+    fn probe(vid: u16, pid: u16, serial: &str) -> DebugProbeInfo {
+        let serial = Some(serial.to_string());
+        DebugProbeInfo::new("probe", vid, pid, serial, DebugProbeType::CmsisDap, None)
+    }
+
+    fn probes() -> Vec<DebugProbeInfo> {
+        vec![
+            probe(0x0483, 0x374b, "066DFF"),
+            probe(0x1366, 0x0101, "000123"),
+            probe(0x1366, 0x0101, "000456"),
+        ]
+    }
+
+    fn select(selector: &str) -> Result<String, String> {
+        let probes = probes();
+        let selector: ProbeSelector = selector.parse()?;
+        selector
+            .select(&probes)
+            .map(|x| x.serial_number.clone().unwrap())
+    }
+
+    #[test]
+    fn serial() {
+        assert_eq!(select("000456"), Ok("000456".to_string()));
+        assert_eq!(select("066DFF"), Ok("066DFF".to_string()));
+        assert!(select("ABCDEF").is_err());
+    }
+
+    #[test]
+    fn index_fallback() {
+        assert_eq!(select("0"), Ok("066DFF".to_string()));
+        assert_eq!(select("2"), Ok("000456".to_string()));
+        assert!(select("3").is_err());
+    }
+
+    #[test]
+    fn usb() {
+        assert_eq!(select("0483:374b"), Ok("066DFF".to_string()));
+        assert_eq!(select("1366:0101:000123"), Ok("000123".to_string()));
+        assert!(select("dead:beef").is_err());
+    }
+
+    #[test]
+    fn ambiguous_usb() {
+        let error = select("1366:0101").unwrap_err();
+        assert!(error.contains("matches 2 probes"), "{}", error);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::command::CommandSink;
+use crate::compression::Compression;
+use crate::coverage::CoverageWriter;
+use crate::decode::{Decoder, DefmtDecoder, DefmtTable, Format};
+use crate::framing::{Deframer, Framing};
+use crate::output::{OpenPolicy, PathContext};
+use crate::pty::PtyWriter;
+use crate::records::{RecordDecoder, Schema};
+use crate::rotation::{RotatingFile, Rotation};
+use crate::tcp::TcpServer;
+use crate::timestamp::{LineStamper, Timestamp};
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+/// Where and how one copy of a channel's data is written
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SinkConfig {
+    /// Output path, `-` writes to stdout. `{channel}`, `{index}`, `{chip}`, `{date}` and
+    /// `{session}` are replaced with their values
+    pub path: Option<PathBuf>,
+    /// Serve the data to any clients connecting to this address instead of writing to `path`
+    pub tcp: Option<SocketAddr>,
+    /// Run this command and write the data to its stdin instead of writing to `path`
+    pub command: Option<Vec<String>>,
+    /// Restart `command` when it exits instead of giving up on this sink
+    #[serde(default)]
+    pub restart: bool,
+    /// What to do if the output file already exists
+    #[serde(default)]
+    pub open: OpenPolicy,
+    /// Compress the output file as it's written
+    #[serde(default)]
+    pub compression: Compression,
+    /// Optional size/age based rotation of the output file
+    pub rotation: Option<Rotation>,
+    /// Split the channel data into frames, each frame is decoded and written as one record
+    pub framing: Option<Framing>,
+    /// How the channel data is decoded before being written
+    #[serde(default)]
+    pub format: Format,
+    /// Schema describing the message enum for the postcard format
+    pub schema: Option<PathBuf>,
+    /// Add the source file and line to decoded defmt messages
+    #[serde(default)]
+    pub location: bool,
+    /// Prefix each line with the host time it was received at, for CBOR and postcard records this
+    /// picks the time fields added to each record instead
+    pub timestamp: Option<Timestamp>,
+    /// With the coverage format, merge the runs into an lcov report at this path when the
+    /// capture ends
+    pub lcov: Option<PathBuf>,
+}
+
+impl SinkConfig {
+    /// Whether this has somewhere to write to
+    pub fn has_destination(&self) -> bool {
+        self.path.is_some() || self.tcp.is_some() || self.command.is_some()
+    }
+}
+
+#[derive(Debug)]
+enum Destination {
+    File(Box<RotatingFile>),
+    Stdout(io::Stdout),
+    Tcp(TcpServer),
+    Pty(PtyWriter),
+    Command(CommandSink),
+    Coverage(CoverageWriter),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::File(f) => f.write(buf),
+            Self::Stdout(s) => s.write(buf),
+            Self::Tcp(t) => t.write(buf),
+            Self::Pty(p) => p.write(buf),
+            Self::Command(c) => c.write(buf),
+            Self::Coverage(c) => c.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(f) => f.flush(),
+            Self::Stdout(s) => s.flush(),
+            Self::Tcp(t) => t.flush(),
+            Self::Pty(p) => p.flush(),
+            Self::Command(c) => c.flush(),
+            Self::Coverage(c) => c.flush(),
+        }
+    }
+}
+
+/// One of the places a channel is written to, with its own decoding and timestamping
+#[derive(Debug)]
+pub struct Output {
+    /// Where this output goes, used in log messages
+    description: String,
+    destination: Destination,
+    deframer: Option<Deframer>,
+    decoder: Decoder,
+    stamper: Option<LineStamper>,
+    pub working: bool,
+}
+
+impl Output {
+    pub fn new(
+        config: &SinkConfig,
+        channel: &str,
+        index: usize,
+        defmt: Option<&'static DefmtTable>,
+        start: Instant,
+        paths: &PathContext,
+        binary: Option<&Path>,
+    ) -> io::Result<Self> {
+        let command = config.command.as_deref();
+        let (destination, description) = match (config.path.as_deref(), config.tcp, command) {
+            (Some(path), None, None)
+                if config.format == Format::Coverage && path != Path::new("-") =>
+            {
+                let lcov = config
+                    .lcov
+                    .as_ref()
+                    .map(|x| paths.expand(x, channel, index));
+                let path = paths.expand(path, channel, index);
+                let writer = CoverageWriter::new(path, config.open, lcov, binary);
+                let description = writer.path().display().to_string();
+                (Destination::Coverage(writer), description)
+            }
+            _ if config.format == Format::Coverage => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("coverage for {} can only be written to files", channel),
+                ))
+            }
+            (None, None, Some(argv)) => {
+                let command = CommandSink::spawn(argv, channel, config.restart)?;
+                let description = format!("'{}'", command.command_line());
+                (Destination::Command(command), description)
+            }
+            (None, Some(addr), None) => {
+                let server = TcpServer::bind(addr, channel)?;
+                let description = format!("tcp://{}", server.local_addr());
+                (Destination::Tcp(server), description)
+            }
+            (Some(path), None, None) if path == Path::new("-") => {
+                (Destination::Stdout(io::stdout()), "stdout".to_string())
+            }
+            (Some(path), None, None) => {
+                let path = paths.expand(path, channel, index);
+                let file = RotatingFile::open(
+                    &path,
+                    config.open,
+                    config.compression,
+                    config.rotation.clone(),
+                )?;
+                let description = file.path().display().to_string();
+                (Destination::File(Box::new(file)), description)
+            }
+            (None, None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("a sink for {} has no path", channel),
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "a sink for {} has more than one of a path, tcp address and command",
+                        channel
+                    ),
+                ))
+            }
+        };
+        info!("Logging {} to {}", channel, description);
+        let records = matches!(config.format, Format::Cbor | Format::Postcard);
+        let stamps_itself = records || config.format == Format::Coverage;
+        // Records need frame boundaries, COBS is the most common way to get them
+        let framing = config.framing.or(Some(Framing::Cobs).filter(|_| records));
+        Ok(Self {
+            description,
+            destination,
+            deframer: framing.map(|x| Deframer::new(x, channel)),
+            decoder: create_decoder(config, channel, defmt, start)?,
+            // Timestamps would corrupt coverage data, and records have their own
+            stamper: config
+                .timestamp
+                .filter(|_| !stamps_itself)
+                .map(|x| LineStamper::new(x, start)),
+            working: true,
+        })
+    }
+
+    /// Passes the raw channel data through to a pseudo-terminal at `path`
+    pub fn pty(writer: PtyWriter, path: &Path) -> Self {
+        Self {
+            description: path.display().to_string(),
+            destination: Destination::Pty(writer),
+            deframer: None,
+            decoder: Decoder::Raw,
+            stamper: None,
+            working: true,
+        }
+    }
+
+    fn try_write(&mut self, data: &[u8]) -> io::Result<()> {
+        let Self {
+            destination,
+            deframer,
+            decoder,
+            stamper,
+            ..
+        } = self;
+        match deframer {
+            Some(deframer) => deframer.push(data, |frame| {
+                write_record(frame, decoder, stamper.as_mut(), destination)
+            }),
+            None => write_record(data, decoder, stamper.as_mut(), destination),
+        }
+    }
+
+    /// Decodes data read from the channel and writes it out, marking the output broken on failure
+    pub fn write(&mut self, data: &[u8]) {
+        if let Err(e) = self.try_write(data) {
+            error!("Failed to write data to {}: {}", self.description, e);
+            self.working = false;
+        }
+    }
+
+    /// Writes a line marking a gap in the data. When `lost` says data is known to be missing, a
+    /// partial line from before the gap is ended first so it isn't joined to what comes after,
+    /// and any partially decoded data is dropped. Otherwise the data read so far is intact,
+    /// decoding carries on and the marker is only written to line or record outputs, since raw
+    /// output may well be binary
+    pub fn mark_gap(&mut self, message: &str, lost: bool) {
+        if lost {
+            if let Some(stamper) = self.stamper.as_mut() {
+                if let Err(e) = stamper.finish(&mut self.destination) {
+                    error!("Failed to write data to {}: {}", self.description, e);
+                    self.working = false;
+                }
+            }
+            self.decoder.reset();
+            if let Some(deframer) = self.deframer.as_mut() {
+                deframer.reset();
+            }
+        }
+        if let Destination::Coverage(c) = &mut self.destination {
+            // A marker would corrupt the profile, end the run instead if it's missing data
+            warn!("{}, the current coverage run may be incomplete", message);
+            if lost {
+                if let Err(e) = c.end_run() {
+                    error!("Failed to write data to {}: {}", self.description, e);
+                    self.working = false;
+                }
+            }
+            return;
+        }
+        if !lost && self.stamper.is_none() && matches!(self.decoder, Decoder::Raw) {
+            return;
+        }
+        let marker = match &self.decoder {
+            Decoder::Record(r) => {
+                let mut marker = vec![];
+                r.write_gap(message, &mut marker).map(|_| marker)
+            }
+            _ => Ok(format!("\n=== rtt-file-logger: {} ===\n", message).into_bytes()),
+        };
+        if let Err(e) = marker.and_then(|x| self.destination.write_all(&x)) {
+            error!("Failed to write data to {}: {}", self.description, e);
+            self.working = false;
+        }
+    }
+
+    /// Flushes compressed file data that has been buffered for too long, called from the poll loop
+    /// so it's written out even when the channel goes quiet
+    pub fn flush_if_due(&mut self) {
+        if let Destination::File(f) = &mut self.destination {
+            if let Err(e) = f.flush_if_due() {
+                error!("Failed to write data to {}: {}", self.description, e);
+                self.working = false;
+            }
+        }
+    }
+
+    /// Writes out anything still buffered and finishes the file, called when the capture ends
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(stamper) = self.stamper.as_mut() {
+            stamper.finish(&mut self.destination)?;
+        }
+        match &mut self.destination {
+            Destination::File(f) => f.finish(),
+            Destination::Stdout(s) => s.flush(),
+            Destination::Tcp(t) => t.flush(),
+            Destination::Pty(p) => p.flush(),
+            Destination::Command(c) => c.finish(),
+            Destination::Coverage(c) => c.finish(),
+        }
+    }
+}
+
+/// Decodes a read or frame and writes it out in one go, so rotation and the queued sinks never
+/// split a decoded line or record
+fn write_record(
+    data: &[u8],
+    decoder: &mut Decoder,
+    stamper: Option<&mut LineStamper>,
+    destination: &mut Destination,
+) -> io::Result<()> {
+    let mut decoded = vec![];
+    decoder.write(data, &mut decoded)?;
+    match stamper {
+        Some(stamper) => stamper.write(&decoded, destination),
+        None if decoded.is_empty() => Ok(()),
+        None => destination.write_all(&decoded),
+    }
+}
+
+/// Creates the decoder producing the text a channel's pass and fail patterns are matched
+/// against. defmt data is matched as decoded messages and anything else as it's received
+pub fn match_decoder(config: Option<&SinkConfig>, defmt: Option<&'static DefmtTable>) -> Decoder {
+    match (config, defmt) {
+        (Some(config), Some(table)) => Decoder::Defmt(DefmtDecoder::new(table, config.location)),
+        _ => Decoder::Raw,
+    }
+}
+
+/// Creates the decoder for an output, falling back to writing raw bytes if it needs the defmt
+/// table and we don't have one
+fn create_decoder(
+    config: &SinkConfig,
+    channel: &str,
+    defmt: Option<&'static DefmtTable>,
+    start: Instant,
+) -> io::Result<Decoder> {
+    let timestamp = config.timestamp.unwrap_or(Timestamp::Wall);
+    Ok(match (config.format, defmt) {
+        (Format::Raw | Format::Coverage, _) => Decoder::Raw,
+        (Format::Hex, _) => Decoder::Hex,
+        (Format::Defmt, Some(table)) => Decoder::Defmt(DefmtDecoder::new(table, config.location)),
+        (Format::Defmt, None) => {
+            warn!("No defmt table for {}, writing raw data", channel);
+            Decoder::Raw
+        }
+        (Format::Cbor, _) => Decoder::Record(RecordDecoder::cbor(channel, timestamp, start)),
+        (Format::Postcard, _) => {
+            let path = config.schema.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("the postcard format for {} needs a schema", channel),
+                )
+            })?;
+            let schema =
+                Schema::load(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Decoder::Record(RecordDecoder::postcard(schema, channel, timestamp, start))
+        }
+    })
+}
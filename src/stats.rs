@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Throughput is measured over windows of this length to find the peak
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Counters for reads from one channel over the whole capture
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub name: String,
+    pub core: usize,
+    pub up: usize,
+    pub bytes: u64,
+    pub reads: u64,
+    pub empty_reads: u64,
+    pub errors: u64,
+    /// Times the target looked to have dropped data because the channel wasn't read in time
+    pub overflows: u64,
+    /// Highest throughput seen over a one second window, in bytes per second
+    pub peak_bytes_per_sec: f64,
+    #[serde(skip)]
+    window_start: Instant,
+    #[serde(skip)]
+    window_bytes: u64,
+}
+
+impl ChannelStats {
+    pub fn new(name: &str, core: usize, up: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            core,
+            up,
+            bytes: 0,
+            reads: 0,
+            empty_reads: 0,
+            errors: 0,
+            overflows: 0,
+            peak_bytes_per_sec: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Records a successful read of `bytes` bytes
+    pub fn read(&mut self, bytes: usize) {
+        self.reads += 1;
+        if bytes == 0 {
+            self.empty_reads += 1;
+        }
+        self.bytes += bytes as u64;
+        self.window_bytes += bytes as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.peak_bytes_per_sec = self.peak_bytes_per_sec.max(rate);
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Records a failed read
+    pub fn error(&mut self) {
+        self.reads += 1;
+        self.errors += 1;
+    }
+
+    pub fn log(&self) {
+        info!(
+            "{}: {} bytes in {} reads ({} empty), {} errors, {} overflows, peak {:.0} B/s",
+            self.name,
+            self.bytes,
+            self.reads,
+            self.empty_reads,
+            self.errors,
+            self.overflows,
+            self.peak_bytes_per_sec
+        );
+    }
+}
+
+/// Machine readable description of a capture, written when it ends
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    /// When the capture started, in RFC 3339 format
+    pub started: String,
+    /// How long the capture ran, in seconds
+    pub duration: f64,
+    /// `stopped`, `passed`, `failed`, `timed_out`, `incomplete` or `error`
+    pub outcome: String,
+    /// What went wrong if the outcome is `error`
+    pub error: Option<String>,
+    pub bytes: u64,
+    pub channels: Vec<ChannelStats>,
+}
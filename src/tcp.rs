@@ -0,0 +1,66 @@
+use crate::queue::QueuedWriter;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{error, info, warn};
+
+#[derive(Debug)]
+struct Client {
+    peer: SocketAddr,
+    writer: QueuedWriter,
+}
+
+/// Listens on a TCP address and forwards everything written to it to all connected clients,
+/// each through its own `QueuedWriter`.
+#[derive(Debug)]
+pub struct TcpServer {
+    addr: SocketAddr,
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl TcpServer {
+    pub fn bind(addr: SocketAddr, name: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => match stream.peer_addr() {
+                        Ok(peer) => {
+                            info!("{} connected to {} on {}", peer, name, addr);
+                            let name = name.clone();
+                            let writer = QueuedWriter::spawn(stream, move |e| {
+                                info!("{} disconnected from {}: {}", peer, name, e)
+                            });
+                            accepted.lock().unwrap().push(Client { peer, writer });
+                        }
+                        Err(e) => warn!("Couldn't get the address of a client: {}", e),
+                    },
+                    Err(e) => error!("Failed to accept a connection on {}: {}", addr, e),
+                }
+            }
+        });
+        Ok(Self { addr, clients })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Write for TcpServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data: Arc<[u8]> = buf.into();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|client| client.writer.send(data.clone(), client.peer));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::SecondsFormat;
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Which host timestamp goes in front of each line
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timestamp {
+    /// Local wall-clock time in RFC 3339 format
+    Wall,
+    /// Seconds since the logger started
+    Monotonic,
+    /// Wall-clock time followed by seconds since the logger started
+    Both,
+}
+
+/// Local wall-clock time in RFC 3339 format
+pub fn wall_time() -> String {
+    chrono::Local::now().to_rfc3339_opts(SecondsFormat::Micros, false)
+}
+
+/// Splits channel output into lines and prefixes each complete line with the host time it was
+/// received at. Partial lines are held until the rest of the line arrives.
+#[derive(Debug)]
+pub struct LineStamper {
+    timestamp: Timestamp,
+    start: Instant,
+    partial: Vec<u8>,
+}
+
+impl LineStamper {
+    pub fn new(timestamp: Timestamp, start: Instant) -> Self {
+        Self {
+            timestamp,
+            start,
+            partial: vec![],
+        }
+    }
+
+    fn prefix(&self) -> String {
+        let wall = wall_time;
+        let monotonic = || format!("{:.6}", self.start.elapsed().as_secs_f64());
+        match self.timestamp {
+            Timestamp::Wall => format!("[{}] ", wall()),
+            Timestamp::Monotonic => format!("[{}] ", monotonic()),
+            Timestamp::Both => format!("[{} {}] ", wall(), monotonic()),
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let mut lines = data.split_inclusive(|x| *x == b'\n').peekable();
+        if lines.peek().is_none() {
+            return Ok(());
+        }
+        let prefix = self.prefix();
+        for line in lines {
+            self.partial.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                let mut stamped = prefix.clone().into_bytes();
+                stamped.append(&mut self.partial);
+                out.write_all(&stamped)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out any partial line, called when the capture ends or before a gap in the data
+    pub fn finish(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if !self.partial.is_empty() {
+            self.partial.push(b'\n');
+            let mut stamped = self.prefix().into_bytes();
+            stamped.append(&mut self.partial);
+            out.write_all(&stamped)?;
+        }
+        Ok(())
+    }
+}